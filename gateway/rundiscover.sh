@@ -0,0 +1,2 @@
+#!/bin/sh
+./target/release/ble-gateway -vvv --discover --name-pattern '^eclipse-iot' --report-interval 10sec
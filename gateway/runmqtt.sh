@@ -0,0 +1,3 @@
+#!/bin/sh
+# Publishes to a local broker, e.g. `mosquitto -v`. Subscribe with `mosquitto_sub -t 'sensors/#' -v`
+./target/release/ble-gateway -d E2:9A:A8:1C:CB:0A --report-interval 10sec --mqtt-host localhost
@@ -1,2 +1,3 @@
 #!/bin/sh
-./target/release/ble-gateway -d E2:9A:A8:1C:CB:0A --report-interval 10sec | while read -r line; do curl -X POST -H "Content-Type: application/json" -d "$line" -u 'microbit@eclipse-iot-day:hey-rodney' https://http.sandbox.drogue.cloud/v1/foo; done
+: "${DROGUE_PASSWORD:?DROGUE_PASSWORD must be set}"
+./target/release/ble-gateway -d E2:9A:A8:1C:CB:0A=microbit --report-interval 10sec --http-url https://http.sandbox.drogue.cloud --application eclipse-iot-day --cloud-device microbit --channel foo
@@ -0,0 +1,3 @@
+#!/bin/sh
+# Streams telemetry and installs the given firmware on boards running a different version
+./target/release/ble-gateway -d E2:9A:A8:1C:CB:0A --report-interval 10sec --firmware "$1" --firmware-version "$2"
@@ -0,0 +1,120 @@
+use crate::metrics;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Consecutive failures of the devices sharing an adapter, to tell a failing adapter from a
+/// failing device.
+pub struct Failures {
+    /// Failures after which every device must have failed for the adapter to be blamed.
+    threshold: Option<u32>,
+    /// Failures of each device since it last worked or the adapter was last power cycled.
+    devices: Mutex<HashMap<String, u32>>,
+}
+
+impl Failures {
+    pub fn new(threshold: Option<u32>) -> Self {
+        Self {
+            threshold,
+            devices: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records that a device works.
+    pub fn succeeded(&self, device: &str) {
+        self.devices.lock().unwrap().insert(device.to_string(), 0);
+    }
+
+    /// Stops tracking a device the gateway gave up on.
+    pub fn forget(&self, device: &str) {
+        self.devices.lock().unwrap().remove(device);
+    }
+
+    /// Records a failure of a device. Returns whether every device failed at least
+    /// `threshold` times in a row since the last power cycle, in which case it is time for
+    /// another one.
+    pub fn failed(&self, device: &str) -> bool {
+        let threshold = match self.threshold {
+            Some(threshold) if threshold > 0 => threshold,
+            _ => return false,
+        };
+        let mut devices = self.devices.lock().unwrap();
+        *devices.entry(device.to_string()).or_insert(0) += 1;
+        if devices.values().all(|failures| *failures >= threshold) {
+            devices.values_mut().for_each(|failures| *failures = 0);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The Bluetooth adapter shared by all device tasks.
+pub struct SharedAdapter {
+    pub adapter: Arc<bluer::Adapter>,
+    pub failures: Failures,
+    power_cycled: Notify,
+}
+
+impl SharedAdapter {
+    pub fn new(adapter: bluer::Adapter, power_cycle_after: Option<u32>) -> Self {
+        Self {
+            adapter: Arc::new(adapter),
+            failures: Failures::new(power_cycle_after),
+            power_cycled: Notify::new(),
+        }
+    }
+
+    /// Powering the adapter off ends discovery, so whoever runs it is told to start it again.
+    pub async fn power_cycle(&self) {
+        metrics::ADAPTER_POWER_CYCLES.inc();
+        let result = async {
+            self.adapter.set_powered(false).await?;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            self.adapter.set_powered(true).await
+        };
+        if let Err(e) = result.await {
+            log::warn!("Error power cycling adapter {}: {}", self.adapter.name(), e);
+        }
+        self.power_cycled.notify_one();
+    }
+
+    /// Completes once the adapter was power cycled.
+    pub async fn power_cycled(&self) {
+        self.power_cycled.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blames_adapter_only_when_all_devices_fail() {
+        let failures = Failures::new(Some(2));
+        failures.succeeded("steady");
+        for _ in 0..5 {
+            assert!(!failures.failed("flaky"));
+        }
+
+        assert!(!failures.failed("steady"));
+        assert!(failures.failed("steady"));
+        // Counted again from the power cycle
+        assert!(!failures.failed("flaky"));
+        assert!(!failures.failed("steady"));
+        assert!(!failures.failed("flaky"));
+        assert!(failures.failed("steady"));
+    }
+
+    #[test]
+    fn ignores_forgotten_devices() {
+        let failures = Failures::new(Some(1));
+        failures.succeeded("gone");
+        assert!(!failures.failed("flaky"));
+
+        failures.forget("gone");
+        assert!(failures.failed("flaky"));
+        assert!(!Failures::new(None).failed("flaky"));
+    }
+}
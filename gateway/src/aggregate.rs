@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Aggregation of readings before they are published.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregationConfig {
+    /// Publish statistics of the readings received within windows this long, instead of
+    /// every reading.
+    #[serde(with = "humantime_serde")]
+    pub window: Option<Duration>,
+    /// Publish this often, for windows sliding in steps. Windows don't overlap if not set.
+    #[serde(with = "humantime_serde")]
+    pub step: Option<Duration>,
+}
+
+impl AggregationConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match (self.window, self.step) {
+            (Some(window), _) if window.is_zero() => {
+                anyhow::bail!("aggregation.window must be greater than 0")
+            }
+            (Some(_), Some(step)) if step.is_zero() => {
+                anyhow::bail!("aggregation.step must be greater than 0")
+            }
+            (Some(window), Some(step)) if step > window => {
+                anyhow::bail!("aggregation.step must not be longer than aggregation.window")
+            }
+            (None, Some(_)) => anyhow::bail!("aggregation.step requires aggregation.window"),
+            _ => Ok(()),
+        }
+    }
+
+    /// A new window for the readings of a device, if aggregation is enabled.
+    pub fn window(&self) -> Option<Window> {
+        self.window
+            .map(|length| Window::new(length, self.step.unwrap_or(length)))
+    }
+}
+
+/// Values of a single reading, such as temperature, within the window.
+struct Samples {
+    unit: serde_json::Value,
+    values: VecDeque<(Instant, f64)>,
+}
+
+/// Readings of a device received within the last `length`, summarized every `step`.
+pub struct Window {
+    length: Duration,
+    step: Duration,
+    samples: BTreeMap<String, Samples>,
+}
+
+impl Window {
+    fn new(length: Duration, step: Duration) -> Self {
+        Self {
+            length,
+            step,
+            samples: BTreeMap::new(),
+        }
+    }
+
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    /// Adds the numeric values of a decoded reading, other fields are ignored.
+    pub fn add(&mut self, now: Instant, reading: &serde_json::Value) {
+        for (name, field) in reading.as_object().into_iter().flatten() {
+            if let Some(value) = field["value"].as_f64() {
+                let samples = self.samples.entry(name.clone()).or_insert_with(|| Samples {
+                    unit: field["unit"].clone(),
+                    values: VecDeque::new(),
+                });
+                samples.values.push_back((now, value));
+            }
+        }
+    }
+
+    /// Mean, minimum, maximum and count of each reading within the window ending now, or
+    /// nothing if there were no readings.
+    pub fn summary(&mut self, now: Instant) -> Option<serde_json::Value> {
+        let mut summary = serde_json::Map::new();
+        for (name, samples) in self.samples.iter_mut() {
+            while matches!(samples.values.front(), Some((t, _)) if now.duration_since(*t) >= self.length)
+            {
+                samples.values.pop_front();
+            }
+            if samples.values.is_empty() {
+                continue;
+            }
+            let values = samples.values.iter().map(|(_, v)| *v);
+            let count = samples.values.len();
+            summary.insert(
+                name.clone(),
+                json!({
+                    "mean": values.clone().sum::<f64>() / count as f64,
+                    "min": values.clone().fold(f64::INFINITY, f64::min),
+                    "max": values.fold(f64::NEG_INFINITY, f64::max),
+                    "count": count,
+                    "unit": samples.unit,
+                }),
+            );
+        }
+        if summary.is_empty() {
+            None
+        } else {
+            Some(summary.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn celsius(value: f64) -> serde_json::Value {
+        json!({ "temperature": { "value": value, "unit": "°C" } })
+    }
+
+    fn window(window: u64, step: Option<u64>) -> Window {
+        AggregationConfig {
+            window: Some(Duration::from_secs(window)),
+            step: step.map(Duration::from_secs),
+        }
+        .window()
+        .unwrap()
+    }
+
+    #[test]
+    fn tumbling_windows_do_not_overlap() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut w = window(60, None);
+        w.add(at(10), &celsius(20.0));
+        w.add(at(30), &celsius(22.0));
+        w.add(at(50), &celsius(24.0));
+        w.add(at(50), &json!({ "device": { "model": "microbit" } }));
+
+        assert_eq!(
+            w.summary(at(60)),
+            Some(json!({
+                "temperature": { "mean": 22.0, "min": 20.0, "max": 24.0, "count": 3, "unit": "°C" }
+            }))
+        );
+        assert_eq!(w.summary(at(120)), None);
+    }
+
+    #[test]
+    fn sliding_windows_overlap() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut w = window(60, Some(30));
+        assert_eq!(w.step(), Duration::from_secs(30));
+
+        w.add(at(10), &celsius(20.0));
+        w.add(at(40), &celsius(30.0));
+        assert_eq!(w.summary(at(60)).unwrap()["temperature"]["count"], 2);
+
+        w.add(at(80), &celsius(40.0));
+        let summary = w.summary(at(90)).unwrap();
+        assert_eq!(summary["temperature"]["count"], 2);
+        assert_eq!(summary["temperature"]["mean"], 35.0);
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        let config = |window: Option<u64>, step: Option<u64>| AggregationConfig {
+            window: window.map(Duration::from_secs),
+            step: step.map(Duration::from_secs),
+        };
+
+        assert!(config(Some(60), Some(30)).validate().is_ok());
+        assert!(config(Some(60), Some(90)).validate().is_err());
+        assert!(config(None, Some(30)).validate().is_err());
+        assert!(config(Some(0), None).validate().is_err());
+    }
+}
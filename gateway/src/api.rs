@@ -0,0 +1,209 @@
+use crate::commands::Command;
+use crate::ess::{MeasurementDescriptor, TriggerSetting};
+use crate::Gateway;
+use chrono::{DateTime, Utc};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Latest known state of a device.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceState {
+    pub address: String,
+    pub connected: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub interval: u8,
+    pub measurement: Option<MeasurementDescriptor>,
+    pub triggers: Vec<TriggerSetting>,
+    pub state: serde_json::Value,
+}
+
+#[derive(Default)]
+pub struct DeviceStates {
+    devices: Mutex<BTreeMap<String, DeviceState>>,
+}
+
+impl DeviceStates {
+    pub fn update<F: FnOnce(&mut DeviceState)>(&self, device: &str, address: &str, f: F) {
+        let mut devices = self.devices.lock().unwrap();
+        let state = devices
+            .entry(device.to_string())
+            .or_insert_with(|| DeviceState {
+                address: address.to_string(),
+                connected: false,
+                last_seen: None,
+                interval: 0,
+                measurement: None,
+                triggers: Vec::new(),
+                state: json!({}),
+            });
+        f(state);
+    }
+
+    pub fn get(&self, device: &str) -> Option<DeviceState> {
+        self.devices.lock().unwrap().get(device).cloned()
+    }
+
+    pub fn address(&self, device: &str) -> Option<String> {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(device)
+            .map(|s| s.address.clone())
+    }
+
+    pub fn all(&self) -> BTreeMap<String, DeviceState> {
+        self.devices.lock().unwrap().clone()
+    }
+}
+
+fn json_response(status: StatusCode, body: &serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &json!({ "error": message }))
+}
+
+async fn handle(gateway: Arc<Gateway>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path: Vec<&str> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let response = match (req.method(), path.as_slice()) {
+        (&Method::GET, ["devices"]) => json_response(StatusCode::OK, &json!(gateway.states.all())),
+        (&Method::GET, ["devices", name]) => match gateway.states.get(name) {
+            Some(state) => json_response(StatusCode::OK, &json!(state)),
+            None => error(StatusCode::NOT_FOUND, "unknown device"),
+        },
+        (&Method::POST, ["devices", name, "interval"]) => {
+            let device = name.to_string();
+            if gateway.states.get(&device).is_none() {
+                return Ok(error(StatusCode::NOT_FOUND, "unknown device"));
+            }
+            let payload = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(payload) => payload.to_vec(),
+                Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &e.to_string())),
+            };
+            let command = Command {
+                device,
+                name: "set-interval".to_string(),
+                payload,
+            };
+            match gateway.commands.dispatch(&command) {
+                Ok(()) => json_response(StatusCode::ACCEPTED, &json!({})),
+                Err(e) => error(StatusCode::BAD_REQUEST, &e),
+            }
+        }
+        _ => error(StatusCode::NOT_FOUND, "not found"),
+    };
+    Ok(response)
+}
+
+/// Serves the local device API:
+///
+/// * `GET /devices` - state of all devices
+/// * `GET /devices/<name>` - state of a single device
+/// * `POST /devices/<name>/interval` - change the reporting interval, with a body of
+///   `{"interval": <seconds>}`
+pub async fn serve(addr: SocketAddr, gateway: Arc<Gateway>) -> hyper::Result<()> {
+    log::info!("Serving device API on http://{}/devices", addr);
+    let make_svc = make_service_fn(move |_| {
+        let gateway = gateway.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(gateway.clone(), req))) }
+    });
+    Server::try_bind(&addr)?.serve(make_svc).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::DeviceCommand;
+
+    async fn request(
+        gateway: &Arc<Gateway>,
+        req: Request<Body>,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = handle(gateway.clone(), req).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_device_state() {
+        let (gateway, _shutdown) = crate::tests::gateway();
+        let gateway = Arc::new(gateway);
+        gateway.states.update("kitchen", "E2:9A:A8:1C:CB:0A", |s| {
+            s.connected = true;
+            s.interval = 10;
+            s.state = json!({ "temperature": { "value": 21.5, "unit": "°C" } });
+        });
+
+        let (status, devices) = request(&gateway, get("/devices")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(devices["kitchen"]["connected"], true);
+
+        let (status, device) = request(&gateway, get("/devices/kitchen")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(device["address"], "E2:9A:A8:1C:CB:0A");
+        assert_eq!(device["interval"], 10);
+        assert_eq!(device["state"]["temperature"]["value"], 21.5);
+
+        let (status, _) = request(&gateway, get("/devices/garage")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn changes_interval() {
+        let (gateway, _shutdown) = crate::tests::gateway();
+        let gateway = Arc::new(gateway);
+        gateway
+            .states
+            .update("kitchen", "E2:9A:A8:1C:CB:0A", |_| {});
+        let mut commands = gateway.commands.register("kitchen");
+        let post = |path: &str, body: &str| {
+            Request::post(path)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let (status, _) = request(
+            &gateway,
+            post("/devices/kitchen/interval", r#"{"interval": 30}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(commands.try_recv().unwrap(), DeviceCommand::SetInterval(30));
+
+        let (status, body) = request(
+            &gateway,
+            post("/devices/kitchen/interval", r#"{"interval": 0}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("interval"));
+
+        let (status, _) = request(
+            &gateway,
+            post("/devices/garage/interval", r#"{"interval": 30}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,142 +1,823 @@
-use anyhow::anyhow;
-use bluer::{
-    gatt::remote::{Characteristic, Service},
-    Adapter, Address, Device,
+use crate::decoders::{Decoder, Registry, TEMPERATURE_CELSIUS_UUID};
+use crate::ess::{
+    MeasurementDescriptor, TriggerSetting, MEASUREMENT_DESCRIPTOR_UUID,
+    TRIGGER_SETTING_DESCRIPTOR_UUID,
 };
+use crate::gatt::{BluerClient, GattClient};
+use crate::recording::DeviceRecorder;
+use bluer::{Adapter, Address};
+use core::fmt;
 use core::pin::Pin;
 use futures::{Stream, StreamExt};
-use serde_json::json;
+use serde::Serialize;
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::time::{sleep, Duration};
 
-pub struct Microbit {
-    adapter: Arc<Adapter>,
-    device: Address,
-    board: Option<Device>,
+pub struct Microbit<C = BluerClient> {
+    client: C,
+    connected: bool,
+    info: Option<DeviceInfo>,
+    measurement: Option<MeasurementDescriptor>,
+    triggers: Vec<TriggerSetting>,
+    decoders: Arc<Registry>,
+    temperature_exponent: Option<i32>,
+    recorder: Option<DeviceRecorder>,
 }
 
-const BOARD_SERVICE_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x0000181A00001000800000805f9b34fb);
-const TEMPERATURE_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00002a1f00001000800000805f9b34fb);
-const INTERVAL_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00002a2100001000800000805f9b34fb);
+#[derive(Debug)]
+pub enum BoardError {
+    /// The device address could not be parsed.
+    InvalidAddress(String),
+    /// The board does not offer a service the gateway needs.
+    ServiceMissing(uuid::Uuid),
+    /// The service is there, but lacks a characteristic the gateway needs.
+    CharacteristicMissing {
+        service: uuid::Uuid,
+        characteristic: uuid::Uuid,
+    },
+    /// A characteristic value was too short or could not be decoded.
+    InvalidPayload {
+        characteristic: uuid::Uuid,
+        reason: String,
+    },
+    /// Communicating with the board failed.
+    Ble(bluer::Error),
+}
+
+impl fmt::Display for BoardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAddress(address) => write!(f, "invalid device address '{}'", address),
+            Self::ServiceMissing(service) => write!(f, "service {} not found", service),
+            Self::CharacteristicMissing {
+                service,
+                characteristic,
+            } => write!(
+                f,
+                "characteristic {} not found in service {}",
+                characteristic, service
+            ),
+            Self::InvalidPayload {
+                characteristic,
+                reason,
+            } => write!(f, "invalid value of {}: {}", characteristic, reason),
+            Self::Ble(e) => write!(f, "BLE error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BoardError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Ble(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<bluer::Error> for BoardError {
+    fn from(e: bluer::Error) -> Self {
+        Self::Ble(e)
+    }
+}
 
-unsafe impl Send for Microbit {}
+impl BoardError {
+    fn short_payload(characteristic: uuid::Uuid, expected: usize, data: &[u8]) -> Self {
+        Self::InvalidPayload {
+            characteristic,
+            reason: format!("expected {} bytes, got {}", expected, data.len()),
+        }
+    }
+}
+
+/// Contents of the Device Information Service, read when connecting.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hardware_revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_revision: Option<String>,
+}
 
-impl Microbit {
-    pub fn new(device: &str, adapter: Arc<Adapter>) -> Self {
+pub const BOARD_SERVICE_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x0000181a00001000800000805f9b34fb);
+const BATTERY_SERVICE_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x0000180f00001000800000805f9b34fb);
+/// Services searched for characteristics with a registered decoder.
+const SENSOR_SERVICE_UUIDS: [uuid::Uuid; 2] = [BOARD_SERVICE_UUID, BATTERY_SERVICE_UUID];
+const TEMPERATURE_CHAR_UUID: uuid::Uuid = TEMPERATURE_CELSIUS_UUID;
+pub const INTERVAL_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2100001000800000805f9b34fb);
+
+pub const DEVICE_INFO_SERVICE_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x0000180a00001000800000805f9b34fb);
+pub const MODEL_NUMBER_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2400001000800000805f9b34fb);
+pub const FIRMWARE_REVISION_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2600001000800000805f9b34fb);
+const HARDWARE_REVISION_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2700001000800000805f9b34fb);
+const MANUFACTURER_NAME_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2900001000800000805f9b34fb);
+
+const FIRMWARE_SERVICE_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001000b0cd11ec871fd45ddf138840);
+const VERSION_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001001b0cd11ec871fd45ddf138840);
+const MTU_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001002b0cd11ec871fd45ddf138840);
+const CONTROL_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001003b0cd11ec871fd45ddf138840);
+const OFFSET_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001005b0cd11ec871fd45ddf138840);
+const FIRMWARE_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001006b0cd11ec871fd45ddf138840);
+
+/// Firmware revision reported by the board firmware, which sends the temperature in whole degrees
+/// instead of the 0.1℃ resolution of the Temperature Celsius characteristic.
+const WHOLE_DEGREES_FIRMWARE_REVISION: &str = "1.0";
+
+const CONTROL_START: u8 = 1;
+const CONTROL_SWAP: u8 = 2;
+
+/// The decoders for boards running the given firmware revision, with the temperature in
+/// `10^temperature_exponent` degrees if set. Otherwise the revision decides: the board firmware
+/// needs a different temperature scale, and so do boards that don't report a revision, as they
+/// can't be told apart from it.
+pub fn decoders_for_firmware(
+    decoders: &Arc<Registry>,
+    firmware: Option<&str>,
+    temperature_exponent: Option<i32>,
+) -> Arc<Registry> {
+    let exponent = match temperature_exponent {
+        Some(exponent) => exponent,
+        None if matches!(firmware, None | Some(WHOLE_DEGREES_FIRMWARE_REVISION)) => 0,
+        None => return decoders.clone(),
+    };
+    match decoders.get(&TEMPERATURE_CELSIUS_UUID) {
+        Some(decoder) if decoder.exponent != exponent => {
+            let mut decoders = (**decoders).clone();
+            decoders.register(
+                TEMPERATURE_CELSIUS_UUID,
+                Decoder {
+                    exponent,
+                    ..decoder.clone()
+                },
+            );
+            Arc::new(decoders)
+        }
+        _ => decoders.clone(),
+    }
+}
+
+impl Microbit<BluerClient> {
+    pub fn new(device: &str, adapter: Arc<Adapter>) -> Result<Self, BoardError> {
+        let address = Address::from_str(device)
+            .map_err(|_| BoardError::InvalidAddress(device.to_string()))?;
+        Ok(Self::with_client(BluerClient::new(address, adapter)))
+    }
+}
+
+impl<C: GattClient> Microbit<C> {
+    pub fn with_client(client: C) -> Self {
         Self {
-            device: Address::from_str(device).unwrap(),
-            adapter,
-            board: None,
+            client,
+            connected: false,
+            info: None,
+            measurement: None,
+            triggers: Vec::new(),
+            decoders: Arc::new(Registry::default()),
+            temperature_exponent: None,
+            recorder: None,
         }
     }
 
-    async fn connect(&mut self) -> bluer::Result<&mut Device> {
-        if self.board.is_none() {
-            loop {
-                if let Ok(device) = self.adapter.device(self.device) {
-                    // Make sure we get a fresh start
-                    let _ = device.disconnect().await;
-                    sleep(Duration::from_secs(2)).await;
-                    match device.is_connected().await {
-                        Ok(false) => {
-                            log::debug!("Connecting...");
-                            loop {
-                                match device.connect().await {
-                                    Ok(()) => break,
-                                    Err(err) => {
-                                        log::info!("Connect error: {}", &err);
-                                    }
-                                }
-                            }
-                            log::debug!("Connected1");
-                            self.board.replace(device);
-                            break;
-                        }
-                        Ok(true) => {
-                            log::debug!("Connected2");
-                            self.board.replace(device);
-                            break;
-                        }
-                        Err(e) => {
-                            log::info!("Error checking connection, retrying: {:?}", e);
-                        }
-                    }
+    /// Uses these decoders instead of the standard ones, to support more sensors.
+    pub fn with_decoders(mut self, decoders: Arc<Registry>) -> Self {
+        self.decoders = decoders;
+        self
+    }
+
+    /// Decodes the temperature in `10^exponent` degrees, whatever firmware the board reports.
+    pub fn with_temperature_exponent(mut self, exponent: Option<i32>) -> Self {
+        self.temperature_exponent = exponent;
+        self
+    }
+
+    /// Records the raw sensor values before they are decoded.
+    pub fn with_recorder(mut self, recorder: DeviceRecorder) -> Self {
+        self.recorder.replace(recorder);
+        self
+    }
+
+    async fn connect(&mut self) -> Result<(), BoardError> {
+        // A single attempt, retrying is up to the caller's reconnect policy
+        if !self.connected {
+            // Make sure we get a fresh start
+            let _ = self.client.disconnect().await;
+            sleep(Duration::from_secs(2)).await;
+            if !self.client.is_connected().await? {
+                log::debug!("Connecting...");
+                self.client.connect().await?;
+            }
+            log::debug!("Connected");
+            self.connected = true;
+            log::trace!("Services: {:?}", self.client.discover_services().await?);
+            match self.read_device_info().await {
+                Ok(info) => {
+                    log::debug!("Device information: {:?}", info);
+                    self.info.replace(info);
                 }
-                sleep(Duration::from_secs(2)).await;
+                Err(e) => log::info!("Error reading device information: {}", e),
+            }
+            if let Err(e) = self.read_sensor_descriptors().await {
+                log::info!("Error reading sensor descriptors: {}", e);
             }
         }
-        Ok(self.board.as_mut().unwrap())
+        Ok(())
+    }
+
+    /// Disconnects from the board, which ends all notification streams.
+    pub async fn disconnect(&mut self) -> Result<(), BoardError> {
+        self.connected = false;
+        Ok(self.client.disconnect().await?)
+    }
+
+    /// Device information read when connecting, if available.
+    pub fn info(&self) -> Option<&DeviceInfo> {
+        self.info.as_ref()
+    }
+
+    /// ES Measurement descriptor of the temperature characteristic, if the board has one.
+    pub fn measurement(&self) -> Option<&MeasurementDescriptor> {
+        self.measurement.as_ref()
+    }
+
+    /// ES Trigger Settings of the temperature characteristic.
+    pub fn triggers(&self) -> &[TriggerSetting] {
+        &self.triggers
+    }
+
+    async fn read_sensor_descriptors(&mut self) -> Result<(), BoardError> {
+        let c = match self
+            .client
+            .find_characteristic(BOARD_SERVICE_UUID, TEMPERATURE_CHAR_UUID)
+            .await?
+        {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let invalid = |descriptor| {
+            move |reason| BoardError::InvalidPayload {
+                characteristic: descriptor,
+                reason,
+            }
+        };
+
+        self.measurement = match self
+            .client
+            .read_descriptors(&c, MEASUREMENT_DESCRIPTOR_UUID)
+            .await?
+            .first()
+        {
+            Some(data) => Some(
+                MeasurementDescriptor::decode(data)
+                    .map_err(invalid(MEASUREMENT_DESCRIPTOR_UUID))?,
+            ),
+            None => None,
+        };
+        self.triggers = self
+            .client
+            .read_descriptors(&c, TRIGGER_SETTING_DESCRIPTOR_UUID)
+            .await?
+            .iter()
+            .map(|data| TriggerSetting::decode(data))
+            .collect::<Result<_, _>>()
+            .map_err(invalid(TRIGGER_SETTING_DESCRIPTOR_UUID))?;
+        log::debug!(
+            "Measurement: {:?}, triggers: {:?}",
+            self.measurement,
+            self.triggers
+        );
+        Ok(())
+    }
+
+    async fn read_device_info(&mut self) -> Result<DeviceInfo, BoardError> {
+        Ok(DeviceInfo {
+            manufacturer: self.read_string(MANUFACTURER_NAME_CHAR_UUID).await?,
+            model: self.read_string(MODEL_NUMBER_CHAR_UUID).await?,
+            hardware_revision: self.read_string(HARDWARE_REVISION_CHAR_UUID).await?,
+            firmware_revision: self.read_string(FIRMWARE_REVISION_CHAR_UUID).await?,
+        })
     }
 
-    pub async fn set_interval(&mut self, i: u8) -> bluer::Result<()> {
+    async fn read_string(&mut self, c: uuid::Uuid) -> Result<Option<String>, BoardError> {
+        match self
+            .client
+            .find_characteristic(DEVICE_INFO_SERVICE_UUID, c)
+            .await?
+        {
+            Some(c) => {
+                let value = self.client.read(&c).await?;
+                Ok(Some(String::from_utf8_lossy(&value).into_owned()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_interval(&mut self, i: u8) -> Result<(), BoardError> {
         self.write_char(BOARD_SERVICE_UUID, INTERVAL_CHAR_UUID, &i.to_le_bytes())
             .await
     }
 
-    fn data_to_json(data: &[u8]) -> serde_json::Value {
-        let temp: i16 = i16::from_le_bytes([data[0], data[1]]);
-        json!({ "temperature": temp })
+    pub async fn interval(&mut self) -> Result<u8, BoardError> {
+        let value = self
+            .read_char(BOARD_SERVICE_UUID, INTERVAL_CHAR_UUID)
+            .await?;
+        value
+            .first()
+            .copied()
+            .ok_or_else(|| BoardError::short_payload(INTERVAL_CHAR_UUID, 1, &value))
     }
 
+    /// Version of the firmware currently running on the board.
+    pub async fn firmware_version(&mut self) -> Result<String, BoardError> {
+        let value = self
+            .read_char(FIRMWARE_SERVICE_UUID, VERSION_CHAR_UUID)
+            .await?;
+        String::from_utf8(value).map_err(|e| BoardError::InvalidPayload {
+            characteristic: VERSION_CHAR_UUID,
+            reason: e.to_string(),
+        })
+    }
+
+    /// Writes new firmware to the board and tells it to swap to it. The board resets once the
+    /// swap is triggered, so the connection is dropped afterwards.
+    pub async fn update_firmware(&mut self, firmware: &[u8]) -> Result<(), BoardError> {
+        let mtu = self.read_char(FIRMWARE_SERVICE_UUID, MTU_CHAR_UUID).await?;
+        let mtu = match mtu.first() {
+            Some(&mtu) if mtu > 0 => mtu as usize,
+            _ => {
+                return Err(BoardError::InvalidPayload {
+                    characteristic: MTU_CHAR_UUID,
+                    reason: format!("invalid MTU {:?}", mtu),
+                })
+            }
+        };
+
+        self.write_char(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, &[CONTROL_START])
+            .await?;
+        let mut progress = 0;
+        for (i, chunk) in firmware.chunks(mtu).enumerate() {
+            let offset = (i * mtu) as u32;
+            self.write_char(
+                FIRMWARE_SERVICE_UUID,
+                OFFSET_CHAR_UUID,
+                &offset.to_le_bytes(),
+            )
+            .await?;
+            self.write_char(FIRMWARE_SERVICE_UUID, FIRMWARE_CHAR_UUID, chunk)
+                .await?;
+
+            let done = (offset as usize + chunk.len()) * 100 / firmware.len();
+            if done >= progress + 10 {
+                progress = done;
+                log::info!("Firmware update {}% complete", progress);
+            }
+        }
+        self.write_char(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, &[CONTROL_SWAP])
+            .await?;
+        self.connected = false;
+        Ok(())
+    }
+
+    /// Subscribes to all sensor characteristics the board has a decoder for. Each stream
+    /// starts with the current value of the characteristic, so there is a reading before the
+    /// first notification. Values that cannot be decoded are passed on as errors, the stream
+    /// ends when the board disconnects.
     pub async fn stream_sensors(
         &mut self,
-    ) -> Result<Pin<Box<impl Stream<Item = serde_json::Value>>>, anyhow::Error> {
-        let sensors = self
-            .stream_char(BOARD_SERVICE_UUID, TEMPERATURE_CHAR_UUID)
-            .await?
-            .map(|data| Self::data_to_json(&data));
+    ) -> Result<Pin<Box<impl Stream<Item = Result<serde_json::Value, BoardError>>>>, BoardError>
+    {
+        self.connect().await?;
+        let firmware = self
+            .info
+            .as_ref()
+            .and_then(|i| i.firmware_revision.as_deref());
+        let decoders = decoders_for_firmware(&self.decoders, firmware, self.temperature_exponent);
+        let recorder = self
+            .recorder
+            .clone()
+            .map(|r| r.with_firmware_revision(firmware));
+        let mut streams = Vec::new();
+        let mut notify_error = None;
+        for service in SENSOR_SERVICE_UUIDS.iter() {
+            for uuid in decoders.characteristics() {
+                let c = match self.client.find_characteristic(*service, *uuid).await? {
+                    Some(c) => c,
+                    None => continue,
+                };
+                match self.client.notify(&c).await {
+                    Ok(notifications) => {
+                        // Read after subscribing, so no change in between goes unnoticed
+                        let current = match self.client.read(&c).await {
+                            Ok(value) => Some(value),
+                            Err(e) => {
+                                log::info!("Error reading initial value of {}: {}", uuid, e);
+                                None
+                            }
+                        };
+                        let (uuid, decoders) = (*uuid, decoders.clone());
+                        let recorder = recorder.clone();
+                        let values = futures::stream::iter(current).chain(notifications);
+                        streams.push(values.map(move |data| {
+                            if let Some(recorder) = &recorder {
+                                recorder.record(uuid, &data);
+                            }
+                            decoders
+                                .get(&uuid)
+                                .unwrap()
+                                .decode(&data)
+                                .map_err(|reason| BoardError::InvalidPayload {
+                                    characteristic: uuid,
+                                    reason,
+                                })
+                        }));
+                    }
+                    Err(e) => {
+                        log::warn!("Error subscribing to {}: {}", uuid, e);
+                        notify_error.replace(e);
+                    }
+                }
+            }
+        }
 
-        Ok(Box::pin(sensors))
+        if streams.is_empty() {
+            if let Some(e) = notify_error {
+                return Err(e.into());
+            }
+            // Tell whether the board lacks the service or just the characteristic
+            self.find_char(BOARD_SERVICE_UUID, TEMPERATURE_CHAR_UUID)
+                .await?;
+            return Err(BoardError::CharacteristicMissing {
+                service: BOARD_SERVICE_UUID,
+                characteristic: TEMPERATURE_CHAR_UUID,
+            });
+        }
+        Ok(Box::pin(futures::stream::select_all(streams)))
     }
 
-    async fn write_char(
+    async fn read_char(
         &mut self,
         service: uuid::Uuid,
         c: uuid::Uuid,
-        value: &[u8],
-    ) -> bluer::Result<()> {
-        let service = self.find_service(service).await?.unwrap();
-        let c = self.find_char(&service, c).await?.unwrap();
-
-        c.write(value).await
+    ) -> Result<Vec<u8>, BoardError> {
+        let c = self.find_char(service, c).await?;
+        Ok(self.client.read(&c).await?)
     }
 
-    async fn stream_char(
+    async fn write_char(
         &mut self,
         service: uuid::Uuid,
         c: uuid::Uuid,
-    ) -> Result<impl Stream<Item = Vec<u8>>, anyhow::Error> {
-        if let Some(service) = self.find_service(service).await? {
-            if let Some(c) = self.find_char(&service, c).await? {
-                return Ok(c.notify().await?);
-            }
-        }
-        Err(anyhow!("Error locating service {} and char {}", service, c))
+        value: &[u8],
+    ) -> Result<(), BoardError> {
+        let c = self.find_char(service, c).await?;
+        Ok(self.client.write(&c, value).await?)
     }
 
     async fn find_char(
         &mut self,
-        service: &Service,
+        service: uuid::Uuid,
         characteristic: uuid::Uuid,
-    ) -> bluer::Result<Option<Characteristic>> {
-        for c in service.characteristics().await? {
-            let uuid = c.uuid().await?;
-            if uuid == characteristic {
-                return Ok(Some(c));
+    ) -> Result<C::Characteristic, BoardError> {
+        self.connect().await?;
+        match self
+            .client
+            .find_characteristic(service, characteristic)
+            .await?
+        {
+            Some(c) => Ok(c),
+            None if self.client.discover_services().await?.contains(&service) => {
+                Err(BoardError::CharacteristicMissing {
+                    service,
+                    characteristic,
+                })
             }
+            None => Err(BoardError::ServiceMissing(service)),
         }
-        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoders::BATTERY_LEVEL_UUID;
+    use crate::mock::MockPeer;
+    use serde_json::json;
+
+    fn celsius(value: f64) -> serde_json::Value {
+        json!({ "temperature": { "value": value, "unit": "°C" } })
     }
 
-    async fn find_service(&mut self, service: uuid::Uuid) -> bluer::Result<Option<Service>> {
-        let device = self.connect().await?;
-        for s in device.services().await? {
-            let uuid = s.uuid().await?;
-            if uuid == service {
-                return Ok(Some(s));
-            }
+    fn microbit(temperature: i16) -> MockPeer {
+        let peer = MockPeer::new();
+        peer.add_characteristic(
+            BOARD_SERVICE_UUID,
+            TEMPERATURE_CHAR_UUID,
+            &temperature.to_le_bytes(),
+        );
+        peer.add_characteristic(BOARD_SERVICE_UUID, INTERVAL_CHAR_UUID, &[5]);
+        // Firmware that sends the temperature in tenths of a degree
+        peer.add_characteristic(
+            DEVICE_INFO_SERVICE_UUID,
+            FIRMWARE_REVISION_CHAR_UUID,
+            b"2.0",
+        );
+        peer
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_interval_writes_characteristic() {
+        let peer = microbit(20);
+        let mut board = Microbit::with_client(peer.client());
+
+        board.set_interval(10).await.unwrap();
+
+        assert!(peer.is_connected());
+        assert_eq!(peer.writes(), vec![(INTERVAL_CHAR_UUID, vec![10])]);
+        assert_eq!(peer.value(INTERVAL_CHAR_UUID), Some(vec![10]));
+        assert_eq!(board.interval().await.unwrap(), 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_sensors_decodes_notifications() {
+        let peer = microbit(20);
+        let mut board = Microbit::with_client(peer.client());
+
+        let mut s = board.stream_sensors().await.unwrap();
+        peer.notify(TEMPERATURE_CHAR_UUID, &215i16.to_le_bytes());
+        peer.notify(TEMPERATURE_CHAR_UUID, &(-30i16).to_le_bytes());
+
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(2.0));
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(21.5));
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(-3.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn decodes_whole_degrees_from_old_firmware() {
+        let peer = microbit(20);
+        peer.add_characteristic(
+            DEVICE_INFO_SERVICE_UUID,
+            FIRMWARE_REVISION_CHAR_UUID,
+            b"1.0",
+        );
+        let mut board = Microbit::with_client(peer.client());
+
+        let mut s = board.stream_sensors().await.unwrap();
+        peer.notify(TEMPERATURE_CHAR_UUID, &(-3i16).to_le_bytes());
+
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(20.0));
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(-3.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn decodes_whole_degrees_without_firmware_revision() {
+        let peer = MockPeer::new();
+        peer.add_characteristic(
+            BOARD_SERVICE_UUID,
+            TEMPERATURE_CHAR_UUID,
+            &20i16.to_le_bytes(),
+        );
+        peer.add_characteristic(BOARD_SERVICE_UUID, INTERVAL_CHAR_UUID, &[5]);
+        let mut board = Microbit::with_client(peer.client());
+
+        let mut s = board.stream_sensors().await.unwrap();
+
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(20.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn configured_temperature_exponent_overrides_firmware_revision() {
+        let peer = microbit(20);
+        peer.add_characteristic(
+            DEVICE_INFO_SERVICE_UUID,
+            FIRMWARE_REVISION_CHAR_UUID,
+            b"1.1",
+        );
+        let mut board = Microbit::with_client(peer.client()).with_temperature_exponent(Some(0));
+
+        let mut s = board.stream_sensors().await.unwrap();
+
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(20.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_all_decodable_characteristics() {
+        let peer = microbit(200);
+        peer.add_characteristic(BATTERY_SERVICE_UUID, BATTERY_LEVEL_UUID, &[90]);
+        let mut board = Microbit::with_client(peer.client());
+        let mut s = board.stream_sensors().await.unwrap();
+
+        let mut initial = vec![
+            s.next().await.unwrap().unwrap(),
+            s.next().await.unwrap().unwrap(),
+        ];
+        initial.sort_by_key(|v| v.to_string());
+        assert_eq!(
+            initial,
+            vec![
+                json!({ "battery": { "value": 90.0, "unit": "%" } }),
+                celsius(20.0)
+            ]
+        );
+
+        peer.notify(BATTERY_LEVEL_UUID, &[89]);
+        assert_eq!(
+            s.next().await.unwrap().unwrap(),
+            json!({ "battery": { "value": 89.0, "unit": "%" } })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_ends_when_peer_disconnects() {
+        let peer = microbit(20);
+        let mut board = Microbit::with_client(peer.client());
+
+        let mut s = board.stream_sensors().await.unwrap();
+        peer.disconnect();
+
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(2.0));
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnect_ends_stream() {
+        let peer = microbit(20);
+        let mut board = Microbit::with_client(peer.client());
+        let mut s = board.stream_sensors().await.unwrap();
+        s.next().await.unwrap().unwrap();
+
+        board.disconnect().await.unwrap();
+
+        assert!(!peer.is_connected());
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_failures_are_returned() {
+        let peer = microbit(20);
+        peer.fail_connects(3);
+        let mut board = Microbit::with_client(peer.client());
+
+        for _ in 0..3 {
+            assert!(matches!(
+                board.set_interval(1).await,
+                Err(BoardError::Ble(_))
+            ));
         }
-        Ok(None)
+        board.set_interval(1).await.unwrap();
+
+        assert!(peer.is_connected());
+        assert_eq!(peer.writes().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_starts_from_fresh_connection() {
+        let peer = microbit(20);
+        peer.client().connect().await.unwrap();
+        let mut s = {
+            let mut board = Microbit::with_client(peer.client());
+            board.stream_sensors().await.unwrap()
+        };
+
+        // A new board instance drops any stale connection before subscribing
+        let mut board = Microbit::with_client(peer.client());
+        let mut fresh = board.stream_sensors().await.unwrap();
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(2.0));
+        assert!(s.next().await.is_none());
+
+        assert_eq!(fresh.next().await.unwrap().unwrap(), celsius(2.0));
+        peer.notify(TEMPERATURE_CHAR_UUID, &220i16.to_le_bytes());
+        assert_eq!(fresh.next().await.unwrap().unwrap(), celsius(22.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_device_information_on_connect() {
+        let peer = microbit(20);
+        peer.add_characteristic(
+            DEVICE_INFO_SERVICE_UUID,
+            MANUFACTURER_NAME_CHAR_UUID,
+            b"Red Hat",
+        );
+        peer.add_characteristic(
+            DEVICE_INFO_SERVICE_UUID,
+            MODEL_NUMBER_CHAR_UUID,
+            b"Eclipse IoT Day",
+        );
+        peer.add_characteristic(
+            DEVICE_INFO_SERVICE_UUID,
+            FIRMWARE_REVISION_CHAR_UUID,
+            b"1.0",
+        );
+        let mut board = Microbit::with_client(peer.client());
+        assert_eq!(board.info(), None);
+
+        board.set_interval(5).await.unwrap();
+
+        let info = board.info().unwrap();
+        assert_eq!(
+            serde_json::to_value(info).unwrap(),
+            json!({
+                "manufacturer": "Red Hat",
+                "model": "Eclipse IoT Day",
+                "firmware_revision": "1.0",
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_sensor_descriptors_on_connect() {
+        let peer = microbit(20);
+        peer.add_descriptor(
+            TEMPERATURE_CHAR_UUID,
+            MEASUREMENT_DESCRIPTOR_UUID,
+            &[0, 0, 2, 0, 0, 0, 5, 0, 0, 1, 0xff],
+        );
+        peer.add_descriptor(
+            TEMPERATURE_CHAR_UUID,
+            TRIGGER_SETTING_DESCRIPTOR_UUID,
+            &[0x01, 5, 0, 0],
+        );
+        let mut board = Microbit::with_client(peer.client());
+
+        board.set_interval(5).await.unwrap();
+
+        assert_eq!(board.measurement().unwrap().update_interval, Some(5));
+        assert_eq!(board.triggers()[0].interval, Some(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_firmware_writes_chunks_and_swaps() {
+        let peer = microbit(20);
+        peer.add_characteristic(FIRMWARE_SERVICE_UUID, VERSION_CHAR_UUID, b"0.1.0");
+        peer.add_characteristic(FIRMWARE_SERVICE_UUID, MTU_CHAR_UUID, &[4]);
+        peer.add_characteristic(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, &[0]);
+        peer.add_characteristic(FIRMWARE_SERVICE_UUID, OFFSET_CHAR_UUID, &[0; 4]);
+        peer.add_characteristic(FIRMWARE_SERVICE_UUID, FIRMWARE_CHAR_UUID, &[]);
+        let mut board = Microbit::with_client(peer.client());
+
+        assert_eq!(board.firmware_version().await.unwrap(), "0.1.0");
+        board
+            .update_firmware(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            peer.writes(),
+            vec![
+                (CONTROL_CHAR_UUID, vec![CONTROL_START]),
+                (OFFSET_CHAR_UUID, 0u32.to_le_bytes().to_vec()),
+                (FIRMWARE_CHAR_UUID, vec![1, 2, 3, 4]),
+                (OFFSET_CHAR_UUID, 4u32.to_le_bytes().to_vec()),
+                (FIRMWARE_CHAR_UUID, vec![5, 6, 7, 8]),
+                (OFFSET_CHAR_UUID, 8u32.to_le_bytes().to_vec()),
+                (FIRMWARE_CHAR_UUID, vec![9, 10]),
+                (CONTROL_CHAR_UUID, vec![CONTROL_SWAP]),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_characteristic_is_an_error() {
+        let peer = MockPeer::new();
+        peer.add_characteristic(BOARD_SERVICE_UUID, INTERVAL_CHAR_UUID, &[5]);
+        let mut board = Microbit::with_client(peer.client());
+
+        assert!(matches!(
+            board.stream_sensors().await,
+            Err(BoardError::CharacteristicMissing {
+                characteristic: TEMPERATURE_CHAR_UUID,
+                ..
+            })
+        ));
+        assert!(matches!(
+            board.firmware_version().await,
+            Err(BoardError::ServiceMissing(FIRMWARE_SERVICE_UUID))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn short_notifications_are_errors() {
+        let peer = microbit(20);
+        let mut board = Microbit::with_client(peer.client());
+        let mut s = board.stream_sensors().await.unwrap();
+        s.next().await.unwrap().unwrap();
+
+        peer.notify(TEMPERATURE_CHAR_UUID, &[1]);
+        peer.notify(TEMPERATURE_CHAR_UUID, &230i16.to_le_bytes());
+        assert!(matches!(
+            s.next().await,
+            Some(Err(BoardError::InvalidPayload { .. }))
+        ));
+        assert_eq!(s.next().await.unwrap().unwrap(), celsius(23.0));
     }
 }
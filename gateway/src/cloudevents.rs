@@ -0,0 +1,164 @@
+use crate::api::DeviceStates;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+/// How telemetry is wrapped before it is published.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventFormat {
+    /// The bare sensor state.
+    Plain,
+    /// A CloudEvent with the attributes and data in the payload.
+    Structured,
+    /// A CloudEvent with the attributes as `ce-` HTTP headers and the data as payload.
+    Binary,
+}
+
+impl core::str::FromStr for EventFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "structured" => Ok(Self::Structured),
+            "binary" => Ok(Self::Binary),
+            _ => Err(format!(
+                "invalid format '{}', must be plain, structured or binary",
+                s
+            )),
+        }
+    }
+}
+
+/// Creates CloudEvents for the readings of the devices behind a gateway.
+#[derive(Clone)]
+pub struct Events {
+    pub gateway: String,
+    pub event_type: String,
+    /// Subject of the events, `{device}` is replaced with the device name.
+    pub subject: Option<String>,
+    pub dataschema: Option<String>,
+    /// Used to look up the address of a device, which identifies it in the event source.
+    pub states: Arc<DeviceStates>,
+}
+
+impl Events {
+    fn attributes(&self, device: &str, payload: &serde_json::Value) -> Vec<(&'static str, String)> {
+        // Readings replayed from the queue carry the time they were received
+        let time = payload["timestamp"]
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(Utc::now);
+
+        // Sending a message again must not make it look like a new event
+        let id = payload["id"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        let address = self
+            .states
+            .address(device)
+            .unwrap_or_else(|| device.to_string());
+
+        let mut attributes = vec![
+            ("specversion", "1.0".to_string()),
+            ("id", id),
+            ("source", format!("{}/{}", self.gateway, address)),
+            ("type", self.event_type.clone()),
+            ("time", time.to_rfc3339()),
+        ];
+        if let Some(subject) = &self.subject {
+            attributes.push(("subject", subject.replace("{device}", device)));
+        }
+        if let Some(dataschema) = &self.dataschema {
+            attributes.push(("dataschema", dataschema.clone()));
+        }
+        attributes
+    }
+
+    /// Wraps the payload in a structured mode CloudEvent.
+    pub fn structured(&self, device: &str, payload: &serde_json::Value) -> serde_json::Value {
+        let mut event = json!({
+            "datacontenttype": "application/json",
+            "data": payload,
+        });
+        for (name, value) in self.attributes(device, payload) {
+            event[name] = json!(value);
+        }
+        event
+    }
+
+    /// HTTP headers for sending the payload as a binary mode CloudEvent.
+    pub fn headers(&self, device: &str, payload: &serde_json::Value) -> Vec<(String, String)> {
+        self.attributes(device, payload)
+            .into_iter()
+            .map(|(name, value)| (format!("ce-{}", name), value))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events() -> Events {
+        let states = Arc::new(DeviceStates::default());
+        states.update("microbit", "E2:9A:A8:1C:CB:0A", |_| {});
+        Events {
+            gateway: "gw1".to_string(),
+            event_type: "io.drogue.event.v1".to_string(),
+            subject: Some("sensors/{device}".to_string()),
+            dataschema: Some("urn:eclipse-iot-day:sensors:v1".to_string()),
+            states,
+        }
+    }
+
+    #[test]
+    fn structured_event_wraps_payload() {
+        let payload = json!({ "temperature": 21, "timestamp": "2022-05-13T10:00:00+00:00" });
+        let event = events().structured("microbit", &payload);
+
+        assert_eq!(event["specversion"], "1.0");
+        assert_eq!(event["source"], "gw1/E2:9A:A8:1C:CB:0A");
+        assert_eq!(event["type"], "io.drogue.event.v1");
+        assert_eq!(event["subject"], "sensors/microbit");
+        assert_eq!(event["time"], "2022-05-13T10:00:00+00:00");
+        assert_eq!(event["dataschema"], "urn:eclipse-iot-day:sensors:v1");
+        assert_eq!(event["data"], payload);
+        assert!(event["id"].is_string());
+    }
+
+    #[test]
+    fn event_id_is_the_message_id() {
+        let payload = json!({ "temperature": 21, "id": "6f1a1c9e-4b1e-4a8e-9d3c-1f2b3c4d5e6f" });
+        let events = events();
+
+        assert_eq!(events.structured("microbit", &payload)["id"], payload["id"]);
+        assert!(events.headers("microbit", &payload).contains(&(
+            "ce-id".to_string(),
+            payload["id"].as_str().unwrap().to_string()
+        )));
+    }
+
+    #[test]
+    fn binary_event_uses_ce_headers() {
+        let headers = events().headers("microbit", &json!({ "temperature": 21 }));
+        let names: Vec<&str> = headers.iter().map(|(n, _)| n.as_str()).collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "ce-specversion",
+                "ce-id",
+                "ce-source",
+                "ce-type",
+                "ce-time",
+                "ce-subject",
+                "ce-dataschema"
+            ]
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+/// A command received from the cloud for a device.
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub device: String,
+    pub name: String,
+    pub payload: Vec<u8>,
+}
+
+/// Commands understood by the devices.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceCommand {
+    SetInterval(u8),
+}
+
+impl DeviceCommand {
+    /// Parses a `set-interval` command. The payload is either `{"interval": <seconds>}` or a
+    /// human readable duration such as `30s`.
+    pub fn parse(command: &Command) -> Result<Self, String> {
+        match command.name.as_str() {
+            "set-interval" => {
+                let payload = std::str::from_utf8(&command.payload)
+                    .map_err(|_| "payload is not valid UTF-8".to_string())?;
+                let seconds = match serde_json::from_str::<serde_json::Value>(payload) {
+                    Ok(value) => value["interval"]
+                        .as_u64()
+                        .ok_or_else(|| "expected {\"interval\": <seconds>}".to_string())?,
+                    Err(_) => humantime::parse_duration(payload.trim().trim_matches('"'))
+                        .map_err(|e| format!("invalid interval: {}", e))?
+                        .as_secs(),
+                };
+                match seconds {
+                    1..=255 => Ok(Self::SetInterval(seconds as u8)),
+                    _ => Err(format!(
+                        "interval must be between 1 and 255 seconds, got {}",
+                        seconds
+                    )),
+                }
+            }
+            other => Err(format!("unknown command '{}'", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::SetInterval(_) => "set-interval",
+        }
+    }
+}
+
+/// Event reporting the outcome of a command.
+pub fn ack(command: &str, result: Result<(), String>) -> serde_json::Value {
+    match result {
+        Ok(()) => json!({ "ack": { "command": command, "status": "ok" } }),
+        Err(e) => json!({ "ack": { "command": command, "status": "failed", "error": e } }),
+    }
+}
+
+/// Routes commands to the task handling the device they are addressed to.
+#[derive(Default)]
+pub struct Dispatcher {
+    devices: Mutex<HashMap<String, mpsc::UnboundedSender<DeviceCommand>>>,
+}
+
+impl Dispatcher {
+    pub fn register(&self, device: &str) -> mpsc::UnboundedReceiver<DeviceCommand> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.devices.lock().unwrap().insert(device.to_string(), tx);
+        rx
+    }
+
+    /// Whether a task handles commands for `device`.
+    pub fn knows(&self, device: &str) -> bool {
+        self.devices.lock().unwrap().contains_key(device)
+    }
+
+    pub fn dispatch(&self, command: &Command) -> Result<(), String> {
+        let parsed = DeviceCommand::parse(command)?;
+        let devices = self.devices.lock().unwrap();
+        match devices.get(&command.device) {
+            Some(tx) => tx
+                .send(parsed)
+                .map_err(|_| format!("device '{}' is not running", command.device)),
+            None => Err(format!("unknown device '{}'", command.device)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(device: &str, name: &str, payload: &str) -> Command {
+        Command {
+            device: device.to_string(),
+            name: name.to_string(),
+            payload: payload.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_set_interval() {
+        assert_eq!(
+            DeviceCommand::parse(&command("a", "set-interval", r#"{"interval": 30}"#)),
+            Ok(DeviceCommand::SetInterval(30))
+        );
+        assert_eq!(
+            DeviceCommand::parse(&command("a", "set-interval", "2m")),
+            Ok(DeviceCommand::SetInterval(120))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert!(DeviceCommand::parse(&command("a", "set-interval", "0s")).is_err());
+        assert!(DeviceCommand::parse(&command("a", "set-interval", "1h")).is_err());
+        assert!(DeviceCommand::parse(&command("a", "set-interval", "{}")).is_err());
+        assert!(DeviceCommand::parse(&command("a", "reboot", "")).is_err());
+    }
+
+    #[test]
+    fn dispatches_to_registered_device() {
+        let dispatcher = Dispatcher::default();
+        let mut rx = dispatcher.register("microbit");
+
+        dispatcher
+            .dispatch(&command("microbit", "set-interval", "10s"))
+            .unwrap();
+        assert_eq!(rx.try_recv().unwrap(), DeviceCommand::SetInterval(10));
+
+        assert!(dispatcher.knows("microbit"));
+        assert!(!dispatcher.knows("other"));
+        assert!(dispatcher
+            .dispatch(&command("other", "set-interval", "10s"))
+            .is_err());
+    }
+}
@@ -0,0 +1,924 @@
+use crate::aggregate::AggregationConfig;
+use crate::cloudevents::EventFormat;
+use crate::decoders::DecodingConfig;
+use crate::discovery::DiscoveryFilter;
+use crate::filter::FilterConfig;
+use crate::reconnect::ReconnectPolicy;
+use crate::simulator::SimulatorConfig;
+use crate::systemd::SystemdConfig;
+use anyhow::Context;
+use clap::Parser;
+use serde::{Deserialize, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Command line flags. These override the settings in the configuration file, defaults are
+/// documented in `gateway.example.toml`.
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// TOML configuration file.
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration and exit.
+    #[clap(long)]
+    pub print_config: bool,
+
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: usize,
+
+    /// Bluetooth adapter to use, e.g. `hci0`.
+    #[clap(long)]
+    adapter: Option<String>,
+
+    /// Device to connect to, as `ADDRESS` or `ADDRESS=NAME`. May be repeated, adds to the
+    /// devices in the configuration file.
+    #[clap(short, long, multiple_occurrences = true)]
+    device: Vec<DeviceSpec>,
+
+    /// File listing devices to connect to, one `ADDRESS` or `ADDRESS=NAME` per line.
+    #[clap(long)]
+    device_file: Option<PathBuf>,
+
+    /// Adopt any device advertising the Environmental Sensing service.
+    #[clap(long, overrides_with = "no_discover")]
+    discover: bool,
+
+    /// Don't adopt devices for advertising the Environmental Sensing service.
+    #[clap(long, overrides_with = "discover")]
+    no_discover: bool,
+
+    /// Adopt any device with a name matching this regular expression.
+    #[clap(long)]
+    name_pattern: Option<regex::Regex>,
+
+    /// Adopt these devices when discovered, named by their address. May be repeated.
+    #[clap(long, multiple_occurrences = true)]
+    include: Vec<bluer::Address>,
+
+    /// Never adopt these discovered devices. May be repeated.
+    #[clap(long, multiple_occurrences = true)]
+    exclude: Vec<bluer::Address>,
+
+    #[clap(short, long, parse(try_from_str=humantime::parse_duration))]
+    report_interval: Option<Duration>,
+
+    /// Drogue Cloud HTTP endpoint to publish telemetry to. Prints to stdout if neither this nor
+    /// an MQTT broker is set.
+    #[clap(long)]
+    http_url: Option<String>,
+
+    #[clap(long)]
+    application: Option<String>,
+
+    /// Device name used when authenticating with the cloud.
+    #[clap(long)]
+    cloud_device: Option<String>,
+
+    #[clap(long)]
+    channel: Option<String>,
+
+    #[clap(long, env = "DROGUE_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    http_timeout: Option<Duration>,
+
+    #[clap(long)]
+    http_retries: Option<usize>,
+
+    /// Wait up to this long for a command to be returned with each HTTP publish.
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    command_timeout: Option<Duration>,
+
+    /// MQTT broker to publish telemetry to.
+    #[clap(long)]
+    mqtt_host: Option<String>,
+
+    #[clap(long)]
+    mqtt_port: Option<u16>,
+
+    #[clap(long)]
+    mqtt_client_id: Option<String>,
+
+    /// Topic to publish to, `{device}` is replaced with the device name.
+    #[clap(long)]
+    mqtt_topic: Option<String>,
+
+    #[clap(long)]
+    mqtt_qos: Option<u8>,
+
+    /// How long to wait for the MQTT client to take a message, while the broker is unreachable.
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    mqtt_timeout: Option<Duration>,
+
+    #[clap(long, overrides_with = "no_mqtt_tls")]
+    mqtt_tls: bool,
+
+    #[clap(long, overrides_with = "mqtt_tls")]
+    no_mqtt_tls: bool,
+
+    #[clap(long)]
+    mqtt_username: Option<String>,
+
+    #[clap(long, env = "MQTT_PASSWORD", hide_env_values = true)]
+    mqtt_password: Option<String>,
+
+    /// Topic filter to receive commands on, e.g. `command/inbox/#`.
+    #[clap(long)]
+    mqtt_command_topic: Option<String>,
+
+    /// Publish readings as `plain` JSON, or as `structured` or `binary` (HTTP only) CloudEvents.
+    #[clap(long)]
+    event_format: Option<EventFormat>,
+
+    /// Identifies this gateway in published messages and in the source of events.
+    #[clap(long, env = "GATEWAY_ID")]
+    gateway_id: Option<String>,
+
+    #[clap(long)]
+    event_type: Option<String>,
+
+    /// URI of the schema the event data adheres to.
+    #[clap(long)]
+    dataschema: Option<String>,
+
+    /// Subject of events, `{device}` is replaced with the device name. Defaults to the HTTP
+    /// channel or the MQTT topic.
+    #[clap(long)]
+    event_subject: Option<String>,
+
+    /// Directory to store readings in until they are published, so they survive uplink outages.
+    #[clap(long)]
+    queue_dir: Option<PathBuf>,
+
+    /// Maximum number of queued readings. The oldest readings are dropped when full.
+    #[clap(long)]
+    queue_max: Option<usize>,
+
+    /// Address to serve Prometheus metrics on, e.g. `0.0.0.0:9100`.
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Address to serve the local device API on, e.g. `127.0.0.1:8080`.
+    #[clap(long)]
+    api_addr: Option<SocketAddr>,
+
+    /// Firmware image to install on devices not running `--firmware-version`.
+    #[clap(long)]
+    firmware: Option<PathBuf>,
+
+    #[clap(long)]
+    firmware_version: Option<String>,
+
+    /// Delay before reconnecting after the first failure, doubling with each further failure.
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    reconnect_initial_delay: Option<Duration>,
+
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    reconnect_max_delay: Option<Duration>,
+
+    /// Stop retrying a device after this many consecutive failures.
+    #[clap(long)]
+    reconnect_max_attempts: Option<u32>,
+
+    /// Power cycle the Bluetooth adapter once every device failed this many times in a row.
+    #[clap(long)]
+    power_cycle_after: Option<u32>,
+
+    /// Append every raw sensor value received to this file.
+    #[clap(long)]
+    record: Option<PathBuf>,
+
+    /// Publish the values of a recording instead of connecting to devices.
+    #[clap(long)]
+    replay: Option<PathBuf>,
+
+    /// Replay this many times faster than the values were recorded.
+    #[clap(long, default_value = "1")]
+    replay_speed: f64,
+
+    /// Publish the mean, minimum, maximum and count of the readings within windows this long,
+    /// instead of every reading.
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    aggregate_window: Option<Duration>,
+
+    /// Publish a window this often, letting windows overlap.
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    aggregate_step: Option<Duration>,
+
+    /// How long to wait for devices to disconnect and pending messages to be published when
+    /// shutting down.
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    shutdown_timeout: Option<Duration>,
+
+    /// Publish readings of simulated boards instead of connecting to devices.
+    #[clap(long, overrides_with = "no_simulate")]
+    simulate: bool,
+
+    #[clap(long, overrides_with = "simulate")]
+    no_simulate: bool,
+
+    /// Notify systemd of readiness and connected devices, and ping its watchdog.
+    #[clap(long, overrides_with = "no_systemd_notify")]
+    systemd_notify: bool,
+
+    #[clap(long, overrides_with = "systemd_notify")]
+    no_systemd_notify: bool,
+}
+
+/// A device to connect to and the name it is published under.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DeviceSpec {
+    pub address: bluer::Address,
+    pub name: String,
+}
+
+impl FromStr for DeviceSpec {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, name) = match s.split_once('=') {
+            Some((address, name)) => (address.trim(), name.trim()),
+            None => (s.trim(), s.trim()),
+        };
+        Ok(Self {
+            address: bluer::Address::from_str(address)
+                .with_context(|| format!("invalid device address '{}'", address))?,
+            name: name.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for DeviceSpec {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<DeviceSpec> for String {
+    fn from(spec: DeviceSpec) -> Self {
+        let address = spec.address.to_string();
+        if spec.name == address {
+            address
+        } else {
+            format!("{}={}", address, spec.name)
+        }
+    }
+}
+
+pub fn read_device_file(path: &Path) -> anyhow::Result<Vec<DeviceSpec>> {
+    let mut devices = Vec::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            devices.push(line.parse()?);
+        }
+    }
+    Ok(devices)
+}
+
+/// Gateway configuration, read from a TOML file and overridden by command line flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Identifies this gateway in published messages and in the source of events.
+    pub gateway_id: String,
+    /// Bluetooth adapter to use, e.g. `hci0`. Uses the default adapter if not set.
+    pub adapter: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub report_interval: Option<Duration>,
+    pub devices: Vec<DeviceSpec>,
+    pub device_file: Option<PathBuf>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub api_addr: Option<SocketAddr>,
+    /// File to append every raw sensor value received to.
+    pub record: Option<PathBuf>,
+    /// Recording to publish instead of connecting to devices. Only set on the command line.
+    #[serde(skip)]
+    pub replay: Option<PathBuf>,
+    #[serde(skip)]
+    pub replay_speed: f64,
+    /// How long to wait for devices to disconnect and pending messages to be published when
+    /// shutting down.
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
+    pub log: Log,
+    pub discovery: Discovery,
+    pub http: Http,
+    pub mqtt: Mqtt,
+    pub events: Events,
+    pub queue: Queue,
+    pub firmware: Firmware,
+    pub reconnect: ReconnectPolicy,
+    pub aggregation: AggregationConfig,
+    pub simulator: SimulatorConfig,
+    pub systemd: SystemdConfig,
+    pub decoding: DecodingConfig,
+    /// Publishing filters by field name, e.g. `[filters.temperature]`.
+    pub filters: FilterConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// One of `error`, `warn`, `info`, `debug` or `trace`.
+    pub level: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Discovery {
+    pub service: bool,
+    pub name_pattern: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub url: Option<String>,
+    pub application: Option<String>,
+    pub device: Option<String>,
+    pub channel: String,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    pub retries: usize,
+    #[serde(with = "humantime_serde")]
+    pub command_timeout: Option<Duration>,
+    // Secrets may be tables, which TOML only allows after plain values
+    pub password: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mqtt {
+    pub host: Option<String>,
+    pub port: u16,
+    pub client_id: String,
+    pub topic: String,
+    pub qos: u8,
+    pub tls: bool,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    pub username: Option<String>,
+    pub command_topic: Option<String>,
+    pub password: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Events {
+    pub format: EventFormat,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub dataschema: Option<String>,
+    pub subject: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Queue {
+    pub dir: Option<PathBuf>,
+    pub max: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Firmware {
+    pub path: Option<PathBuf>,
+    pub version: Option<String>,
+}
+
+/// A credential, given directly or read from an environment variable or file when needed.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl Secret {
+    pub fn resolve(&self) -> anyhow::Result<String> {
+        match self {
+            Self::Value(value) => Ok(value.clone()),
+            Self::Env { env } => {
+                std::env::var(env).with_context(|| format!("reading secret from ${}", env))
+            }
+            Self::File { file } => Ok(std::fs::read_to_string(file)
+                .with_context(|| format!("reading secret from {}", file.display()))?
+                .trim_end()
+                .to_string()),
+        }
+    }
+}
+
+impl core::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Value(_) => write!(f, "Value(<redacted>)"),
+            Self::Env { env } => write!(f, "Env({})", env),
+            Self::File { file } => write!(f, "File({})", file.display()),
+        }
+    }
+}
+
+// Only references to secrets are printed, never their values
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum Printed<'a> {
+            Value(&'a str),
+            Env { env: &'a str },
+            File { file: &'a Path },
+        }
+        match self {
+            Self::Value(_) => Printed::Value("<redacted>"),
+            Self::Env { env } => Printed::Env { env },
+            Self::File { file } => Printed::File { file },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            gateway_id: "ble-gateway".to_string(),
+            adapter: None,
+            report_interval: None,
+            devices: Vec::new(),
+            device_file: None,
+            metrics_addr: None,
+            api_addr: None,
+            record: None,
+            replay: None,
+            replay_speed: 1.0,
+            shutdown_timeout: Duration::from_secs(10),
+            log: Default::default(),
+            discovery: Default::default(),
+            http: Default::default(),
+            mqtt: Default::default(),
+            events: Default::default(),
+            queue: Default::default(),
+            firmware: Default::default(),
+            reconnect: Default::default(),
+            aggregation: Default::default(),
+            simulator: Default::default(),
+            systemd: Default::default(),
+            decoding: Default::default(),
+            filters: Default::default(),
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: "error".to_string(),
+        }
+    }
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            url: None,
+            application: None,
+            device: None,
+            channel: "sensors".to_string(),
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            command_timeout: None,
+            password: None,
+        }
+    }
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: 1883,
+            client_id: "ble-gateway".to_string(),
+            topic: "sensors/{device}".to_string(),
+            qos: 1,
+            tls: false,
+            timeout: Duration::from_secs(10),
+            username: None,
+            command_topic: None,
+            password: None,
+        }
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            format: EventFormat::Plain,
+            event_type: "io.drogue.event.v1".to_string(),
+            dataschema: None,
+            subject: None,
+        }
+    }
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max: 10000,
+        }
+    }
+}
+
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("parsing config file {}", path.display()))
+    }
+
+    /// Overrides the configuration with the flags given on the command line.
+    pub fn apply(&mut self, args: Args) {
+        fn set<T>(target: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+        fn set_opt<T>(target: &mut Option<T>, value: Option<T>) {
+            if value.is_some() {
+                *target = value;
+            }
+        }
+        // Switches have a `--no-` flag to turn them off, the last one given wins
+        fn set_switch(target: &mut bool, on: bool, off: bool) {
+            if on || off {
+                *target = on;
+            }
+        }
+
+        if args.verbose > 0 {
+            let level = args.verbose.min(LOG_LEVELS.len() - 1);
+            self.log.level = LOG_LEVELS[level].to_string();
+        }
+        set(&mut self.gateway_id, args.gateway_id);
+        set_opt(&mut self.adapter, args.adapter);
+        set_opt(&mut self.report_interval, args.report_interval);
+        self.devices.extend(args.device);
+        set_opt(&mut self.device_file, args.device_file);
+        set_opt(&mut self.metrics_addr, args.metrics_addr);
+        set_opt(&mut self.api_addr, args.api_addr);
+        set_opt(&mut self.record, args.record);
+        set_opt(&mut self.replay, args.replay);
+        self.replay_speed = args.replay_speed;
+        set(&mut self.shutdown_timeout, args.shutdown_timeout);
+
+        set_switch(&mut self.discovery.service, args.discover, args.no_discover);
+        set_opt(
+            &mut self.discovery.name_pattern,
+            args.name_pattern.map(|r| r.as_str().to_string()),
+        );
+        self.discovery
+            .include
+            .extend(args.include.iter().map(|a| a.to_string()));
+        self.discovery
+            .exclude
+            .extend(args.exclude.iter().map(|a| a.to_string()));
+
+        set_opt(&mut self.http.url, args.http_url);
+        set_opt(&mut self.http.application, args.application);
+        set_opt(&mut self.http.device, args.cloud_device);
+        set(&mut self.http.channel, args.channel);
+        set_opt(&mut self.http.password, args.password.map(Secret::Value));
+        set(&mut self.http.timeout, args.http_timeout);
+        set(&mut self.http.retries, args.http_retries);
+        set_opt(&mut self.http.command_timeout, args.command_timeout);
+
+        set_opt(&mut self.mqtt.host, args.mqtt_host);
+        set(&mut self.mqtt.port, args.mqtt_port);
+        set(&mut self.mqtt.client_id, args.mqtt_client_id);
+        set(&mut self.mqtt.topic, args.mqtt_topic);
+        set(&mut self.mqtt.qos, args.mqtt_qos);
+        set(&mut self.mqtt.timeout, args.mqtt_timeout);
+        set_switch(&mut self.mqtt.tls, args.mqtt_tls, args.no_mqtt_tls);
+        set_opt(&mut self.mqtt.username, args.mqtt_username);
+        set_opt(
+            &mut self.mqtt.password,
+            args.mqtt_password.map(Secret::Value),
+        );
+        set_opt(&mut self.mqtt.command_topic, args.mqtt_command_topic);
+
+        set(&mut self.events.format, args.event_format);
+        set(&mut self.events.event_type, args.event_type);
+        set_opt(&mut self.events.dataschema, args.dataschema);
+        set_opt(&mut self.events.subject, args.event_subject);
+
+        set_opt(&mut self.queue.dir, args.queue_dir);
+        set(&mut self.queue.max, args.queue_max);
+
+        set_opt(&mut self.firmware.path, args.firmware);
+        set_opt(&mut self.firmware.version, args.firmware_version);
+
+        set(
+            &mut self.reconnect.initial_delay,
+            args.reconnect_initial_delay,
+        );
+        set(&mut self.reconnect.max_delay, args.reconnect_max_delay);
+        set_opt(
+            &mut self.reconnect.max_attempts,
+            args.reconnect_max_attempts,
+        );
+        set_opt(
+            &mut self.reconnect.power_cycle_after,
+            args.power_cycle_after,
+        );
+
+        set_opt(&mut self.aggregation.window, args.aggregate_window);
+        set_opt(&mut self.aggregation.step, args.aggregate_step);
+
+        set_switch(&mut self.simulator.enabled, args.simulate, args.no_simulate);
+        set_switch(
+            &mut self.systemd.notify,
+            args.systemd_notify,
+            args.no_systemd_notify,
+        );
+    }
+
+    /// Verbosity to initialize logging with.
+    pub fn verbosity(&self) -> anyhow::Result<usize> {
+        LOG_LEVELS
+            .iter()
+            .position(|l| *l == self.log.level)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "log.level '{}' must be one of {}",
+                    self.log.level,
+                    LOG_LEVELS.join(", ")
+                )
+            })
+    }
+
+    /// Reporting interval in seconds, as the board expects it.
+    pub fn report_interval(&self) -> anyhow::Result<u8> {
+        let interval = self
+            .report_interval
+            .ok_or_else(|| anyhow::anyhow!("report_interval must be set"))?;
+        match interval.as_secs() {
+            secs @ 1..=255 => Ok(secs as u8),
+            _ => anyhow::bail!(
+                "report_interval must be between 1s and 255s, got {}",
+                humantime::format_duration(interval)
+            ),
+        }
+    }
+
+    /// All devices to connect to, including those listed in the device file.
+    pub fn all_devices(&self) -> anyhow::Result<Vec<DeviceSpec>> {
+        let mut devices = self.devices.clone();
+        if let Some(path) = &self.device_file {
+            devices.extend(
+                read_device_file(path)
+                    .with_context(|| format!("reading device file {}", path.display()))?,
+            );
+        }
+        Ok(devices)
+    }
+
+    pub fn discovery_filter(&self) -> anyhow::Result<DiscoveryFilter> {
+        let addresses = |list: &[String], field: &str| {
+            list.iter()
+                .map(|a| {
+                    a.parse()
+                        .with_context(|| format!("discovery.{} has invalid address '{}'", field, a))
+                })
+                .collect::<anyhow::Result<Vec<bluer::Address>>>()
+        };
+        Ok(DiscoveryFilter {
+            service: self.discovery.service,
+            name: self
+                .discovery
+                .name_pattern
+                .as_deref()
+                .map(regex::Regex::new)
+                .transpose()
+                .context("discovery.name_pattern is not a valid regular expression")?,
+            include: addresses(&self.discovery.include, "include")?,
+            exclude: addresses(&self.discovery.exclude, "exclude")?,
+        })
+    }
+
+    pub fn mqtt_qos(&self) -> anyhow::Result<rumqttc::QoS> {
+        crate::mqtt::parse_qos(&self.mqtt.qos.to_string())
+            .map_err(|e| anyhow::anyhow!("mqtt.qos: {}", e))
+    }
+
+    /// Subject of the events, unless set it is where they are published to.
+    pub fn event_subject(&self) -> Option<String> {
+        if self.events.subject.is_some() {
+            self.events.subject.clone()
+        } else if self.http.url.is_some() {
+            Some(self.http.channel.clone())
+        } else if self.mqtt.host.is_some() {
+            Some(self.mqtt.topic.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Checks that the configuration is complete and consistent.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.verbosity()?;
+        let filter = self.discovery_filter()?;
+        if self.replay.is_some() {
+            if self.record.is_some() {
+                anyhow::bail!("A recording cannot be recorded while it is replayed");
+            }
+            if !(self.replay_speed.is_finite() && self.replay_speed > 0.0) {
+                anyhow::bail!("replay speed must be greater than 0");
+            }
+        } else {
+            // Replays don't talk to devices, so only they can do without an interval
+            self.report_interval()?;
+            if self.all_devices()?.is_empty() && !filter.enabled() && !self.simulator.enabled {
+                anyhow::bail!(
+                    "No devices configured, set devices, device_file, discovery.service, \
+                     discovery.name_pattern or discovery.include"
+                );
+            }
+        }
+
+        if self.http.url.is_some() {
+            if self.mqtt.host.is_some() {
+                anyhow::bail!("Only one of http.url and mqtt.host may be set");
+            }
+            if self.http.application.is_none() || self.http.device.is_none() {
+                anyhow::bail!("http.url requires http.application and http.device");
+            }
+        } else if self.events.format == EventFormat::Binary {
+            anyhow::bail!("Binary CloudEvents (events.format) can only be published with http.url");
+        }
+        if self.mqtt.host.is_some() && self.mqtt.client_id.trim().is_empty() {
+            anyhow::bail!("mqtt.client_id must not be empty, the broker keeps the session by it");
+        }
+        self.mqtt_qos()?;
+
+        if self.firmware.path.is_some() && self.firmware.version.is_none() {
+            anyhow::bail!("firmware.path requires firmware.version");
+        }
+        self.reconnect.validate()?;
+        self.aggregation.validate()?;
+        crate::filter::validate(&self.filters)?;
+        self.simulator.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    const CONFIG: &str = r#"
+        gateway_id = "gw1"
+        report_interval = "10s"
+        devices = ["E2:9A:A8:1C:CB:0A=microbit"]
+
+        [log]
+        level = "info"
+
+        [http]
+        url = "https://http.sandbox.drogue.cloud"
+        application = "eclipse-iot-day"
+        device = "microbit"
+        password = { env = "DROGUE_PASSWORD" }
+    "#;
+
+    #[test]
+    fn parses_config_file() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.gateway_id, "gw1");
+        assert_eq!(config.report_interval().unwrap(), 10);
+        assert_eq!(config.devices[0].name, "microbit");
+        assert_eq!(config.verbosity().unwrap(), 2);
+        assert_eq!(config.http.channel, "sensors");
+        assert!(matches!(config.http.password, Some(Secret::Env { .. })));
+    }
+
+    #[test]
+    fn flags_override_config_file() {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        config.apply(Args::parse_from([
+            "ble-gateway",
+            "-vvv",
+            "--report-interval",
+            "30s",
+            "--channel",
+            "foo",
+            "--password",
+            "secret",
+        ]));
+
+        assert_eq!(config.report_interval().unwrap(), 30);
+        assert_eq!(config.verbosity().unwrap(), 3);
+        assert_eq!(config.http.channel, "foo");
+        assert_eq!(config.http.application.as_deref(), Some("eclipse-iot-day"));
+        assert_eq!(config.http.password.unwrap().resolve().unwrap(), "secret");
+    }
+
+    #[test]
+    fn switches_turn_settings_off() {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        config.mqtt.tls = true;
+        config.simulator.enabled = true;
+        config.apply(Args::parse_from([
+            "ble-gateway",
+            "--no-mqtt-tls",
+            "--no-simulate",
+            "--no-discover",
+            "--discover",
+        ]));
+
+        assert!(!config.mqtt.tls);
+        assert!(!config.simulator.enabled);
+        assert!(config.discovery.service);
+        assert!(!config.systemd.notify);
+    }
+
+    #[test]
+    fn event_subject_follows_uplink() {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(config.event_subject().as_deref(), Some("sensors"));
+
+        config.http.url = None;
+        config.mqtt.host = Some("localhost".to_string());
+        assert_eq!(config.event_subject().as_deref(), Some("sensors/{device}"));
+
+        config.events.subject = Some("temperature".to_string());
+        assert_eq!(config.event_subject().as_deref(), Some("temperature"));
+    }
+
+    #[test]
+    fn reports_invalid_config() {
+        let error = |toml: &str| {
+            toml::from_str::<Config>(toml)
+                .map_err(anyhow::Error::from)
+                .and_then(|c| c.validate())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(error("report_interval = \"10s\"").contains("No devices configured"));
+        assert!(error("devices = [\"nonsense\"]").contains("nonsense"));
+        assert!(
+            error("report_interval = \"10s\"\n[discovery]\nname_pattern = \"(\"")
+                .contains("name_pattern")
+        );
+        assert!(error("report_interval = \"10m\"\n[discovery]\nservice = true").contains("255s"));
+        assert!(error("[mqtt]\nhots = \"localhost\"").contains("hots"));
+        assert!(error(
+            "report_interval = \"10s\"\ndevices = [\"E2:9A:A8:1C:CB:0A\"]\n\
+             [mqtt]\nhost = \"localhost\"\nclient_id = \"\""
+        )
+        .contains("client_id"));
+        assert!(error(
+            "report_interval = \"10s\"\ndevices = [\"E2:9A:A8:1C:CB:0A\"]\n\
+             [mqtt]\nhost = \"localhost\"\nqos = 3"
+        )
+        .contains("QoS"));
+
+        let mut replay = Config {
+            replay: Some(PathBuf::from("recording.jsonl")),
+            ..Default::default()
+        };
+        replay.validate().unwrap();
+        for speed in [0.0, f64::NAN, f64::INFINITY] {
+            replay.replay_speed = speed;
+            assert!(replay.validate().unwrap_err().to_string().contains("speed"));
+        }
+    }
+
+    #[test]
+    fn print_config_redacts_secrets() {
+        let mut config = Config::default();
+        config.mqtt.password = Some(Secret::Value("secret".to_string()));
+        config.http.password = Some(Secret::Env {
+            env: "DROGUE_PASSWORD".to_string(),
+        });
+        let printed = toml::to_string_pretty(&config).unwrap();
+
+        assert!(!printed.contains("secret"));
+        assert!(printed.contains("<redacted>"));
+        assert!(printed.contains("DROGUE_PASSWORD"));
+    }
+}
@@ -0,0 +1,215 @@
+//! Decoding of sensor characteristic values, as defined by the GATT specification.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+const fn sig_uuid(short: u16) -> uuid::Uuid {
+    uuid::Uuid::from_u128(((short as u128) << 96) | 0x00001000800000805f9b34fb)
+}
+
+pub const BATTERY_LEVEL_UUID: uuid::Uuid = sig_uuid(0x2a19);
+pub const TEMPERATURE_CELSIUS_UUID: uuid::Uuid = sig_uuid(0x2a1f);
+pub const TEMPERATURE_FAHRENHEIT_UUID: uuid::Uuid = sig_uuid(0x2a20);
+pub const ELEVATION_UUID: uuid::Uuid = sig_uuid(0x2a6c);
+pub const PRESSURE_UUID: uuid::Uuid = sig_uuid(0x2a6d);
+pub const TEMPERATURE_UUID: uuid::Uuid = sig_uuid(0x2a6e);
+pub const HUMIDITY_UUID: uuid::Uuid = sig_uuid(0x2a6f);
+pub const UV_INDEX_UUID: uuid::Uuid = sig_uuid(0x2a76);
+pub const IRRADIANCE_UUID: uuid::Uuid = sig_uuid(0x2a77);
+pub const DEW_POINT_UUID: uuid::Uuid = sig_uuid(0x2a7b);
+
+/// Little-endian integer formats used by characteristic values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    U8,
+    I8,
+    U16,
+    I16,
+    I24,
+    U32,
+}
+
+impl Format {
+    fn len(&self) -> usize {
+        match self {
+            Self::U8 | Self::I8 => 1,
+            Self::U16 | Self::I16 => 2,
+            Self::I24 => 3,
+            Self::U32 => 4,
+        }
+    }
+
+    fn read(&self, d: &[u8]) -> i64 {
+        match self {
+            Self::U8 => d[0] as i64,
+            Self::I8 => d[0] as i8 as i64,
+            Self::U16 => u16::from_le_bytes([d[0], d[1]]) as i64,
+            Self::I16 => i16::from_le_bytes([d[0], d[1]]) as i64,
+            // Sign extend by placing the value in the upper bytes
+            Self::I24 => (i32::from_le_bytes([0, d[0], d[1], d[2]]) >> 8) as i64,
+            Self::U32 => u32::from_le_bytes([d[0], d[1], d[2], d[3]]) as i64,
+        }
+    }
+}
+
+/// Turns the raw value of a characteristic into a named reading with a unit. The value is
+/// `raw * 10^exponent`, the way the GATT specification gives resolutions.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub format: Format,
+    pub exponent: i32,
+}
+
+impl Decoder {
+    pub const fn new(
+        name: &'static str,
+        unit: &'static str,
+        format: Format,
+        exponent: i32,
+    ) -> Self {
+        Self {
+            name,
+            unit,
+            format,
+            exponent,
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<serde_json::Value, String> {
+        if data.len() < self.format.len() {
+            return Err(format!(
+                "expected {} bytes, got {}",
+                self.format.len(),
+                data.len()
+            ));
+        }
+        let raw = self.format.read(data) as f64;
+        // Dividing keeps values like 21.5 exact, where multiplying by 0.1 would not
+        let value = if self.exponent < 0 {
+            raw / 10f64.powi(-self.exponent)
+        } else {
+            raw * 10f64.powi(self.exponent)
+        };
+        Ok(json!({ self.name: { "value": value, "unit": self.unit } }))
+    }
+}
+
+/// How to decode values whose resolution the boards don't tell.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecodingConfig {
+    /// Resolution of the temperature sent by the boards, as a power of ten: `0` for whole
+    /// degrees, `-1` for tenths. Follows from the firmware revision of each board if not set.
+    pub temperature_exponent: Option<i32>,
+}
+
+/// Decoders by characteristic UUID. Supporting another sensor is a matter of registering a
+/// decoder for its characteristic.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    decoders: HashMap<uuid::Uuid, Decoder>,
+}
+
+impl Registry {
+    pub fn empty() -> Self {
+        Self {
+            decoders: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, characteristic: uuid::Uuid, decoder: Decoder) {
+        self.decoders.insert(characteristic, decoder);
+    }
+
+    pub fn get(&self, characteristic: &uuid::Uuid) -> Option<&Decoder> {
+        self.decoders.get(characteristic)
+    }
+
+    pub fn characteristics(&self) -> impl Iterator<Item = &uuid::Uuid> {
+        self.decoders.keys()
+    }
+}
+
+impl Default for Registry {
+    /// Decoders for the standard Environmental Sensing and Battery characteristics.
+    fn default() -> Self {
+        use Format::*;
+        let mut registry = Self::empty();
+        registry.register(BATTERY_LEVEL_UUID, Decoder::new("battery", "%", U8, 0));
+        registry.register(
+            TEMPERATURE_CELSIUS_UUID,
+            Decoder::new("temperature", "°C", I16, -1),
+        );
+        registry.register(
+            TEMPERATURE_FAHRENHEIT_UUID,
+            Decoder::new("temperature", "°F", I16, -1),
+        );
+        registry.register(ELEVATION_UUID, Decoder::new("elevation", "m", I24, -2));
+        registry.register(PRESSURE_UUID, Decoder::new("pressure", "Pa", U32, -1));
+        registry.register(TEMPERATURE_UUID, Decoder::new("temperature", "°C", I16, -2));
+        registry.register(HUMIDITY_UUID, Decoder::new("humidity", "%", U16, -2));
+        registry.register(UV_INDEX_UUID, Decoder::new("uv_index", "", U8, 0));
+        registry.register(IRRADIANCE_UUID, Decoder::new("irradiance", "W/m²", U16, -1));
+        registry.register(DEW_POINT_UUID, Decoder::new("dew_point", "°C", I8, 0));
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(c: uuid::Uuid, data: &[u8]) -> serde_json::Value {
+        Registry::default().get(&c).unwrap().decode(data).unwrap()
+    }
+
+    #[test]
+    fn applies_spec_resolution() {
+        assert_eq!(
+            decode(TEMPERATURE_UUID, &2150i16.to_le_bytes()),
+            json!({ "temperature": { "value": 21.5, "unit": "°C" } })
+        );
+        assert_eq!(
+            decode(TEMPERATURE_CELSIUS_UUID, &(-35i16).to_le_bytes()),
+            json!({ "temperature": { "value": -3.5, "unit": "°C" } })
+        );
+        assert_eq!(
+            decode(HUMIDITY_UUID, &4525u16.to_le_bytes()),
+            json!({ "humidity": { "value": 45.25, "unit": "%" } })
+        );
+        assert_eq!(
+            decode(PRESSURE_UUID, &1013250u32.to_le_bytes()),
+            json!({ "pressure": { "value": 101325.0, "unit": "Pa" } })
+        );
+        assert_eq!(
+            decode(ELEVATION_UUID, &[0x9c, 0xff, 0xff]),
+            json!({ "elevation": { "value": -1.0, "unit": "m" } })
+        );
+        assert_eq!(
+            decode(BATTERY_LEVEL_UUID, &[87]),
+            json!({ "battery": { "value": 87.0, "unit": "%" } })
+        );
+    }
+
+    #[test]
+    fn rejects_short_values() {
+        let registry = Registry::default();
+        let decoder = registry.get(&PRESSURE_UUID).unwrap();
+        assert!(decoder.decode(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn registers_custom_decoders() {
+        let co2 = uuid::Uuid::from_u128(0x0000aa0000001000800000805f9b34fb);
+        let mut registry = Registry::empty();
+        registry.register(co2, Decoder::new("co2", "ppm", Format::U16, 0));
+
+        assert_eq!(
+            registry.get(&co2).unwrap().decode(&412u16.to_le_bytes()),
+            Ok(json!({ "co2": { "value": 412.0, "unit": "ppm" } }))
+        );
+    }
+}
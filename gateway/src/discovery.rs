@@ -0,0 +1,91 @@
+use crate::board::BOARD_SERVICE_UUID;
+use bluer::{Address, Device};
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Decides which discovered devices are adopted by the gateway without being configured
+/// explicitly. A device is adopted if it meets any of the criteria, unless it is excluded.
+pub struct DiscoveryFilter {
+    /// Adopt devices advertising the Environmental Sensing service.
+    pub service: bool,
+    /// Adopt devices with a name matching this pattern.
+    pub name: Option<Regex>,
+    /// Adopt these devices.
+    pub include: Vec<Address>,
+    /// Devices that are never adopted.
+    pub exclude: Vec<Address>,
+}
+
+impl DiscoveryFilter {
+    pub fn enabled(&self) -> bool {
+        self.service || self.name.is_some() || !self.include.is_empty()
+    }
+
+    pub async fn matches(&self, device: &Device) -> bluer::Result<bool> {
+        // Only what the criteria need is read
+        let uuids = match self.service {
+            true => device.uuids().await?.unwrap_or_default(),
+            false => HashSet::new(),
+        };
+        let name = match self.name {
+            Some(_) => device.name().await?,
+            None => None,
+        };
+        Ok(self.adopts(device.address(), &uuids, name.as_deref()))
+    }
+
+    /// Whether to adopt a device advertising these services and name.
+    fn adopts(&self, address: Address, uuids: &HashSet<uuid::Uuid>, name: Option<&str>) -> bool {
+        if self.exclude.contains(&address) {
+            return false;
+        }
+        let named = match (&self.name, name) {
+            (Some(pattern), Some(name)) => pattern.is_match(name),
+            _ => false,
+        };
+        self.include.contains(&address)
+            || (self.service && uuids.contains(&BOARD_SERVICE_UUID))
+            || named
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> DiscoveryFilter {
+        DiscoveryFilter {
+            service: false,
+            name: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn criteria_adopt_devices_on_their_own() {
+        let a: Address = "E2:9A:A8:1C:CB:0A".parse().unwrap();
+        let b: Address = "E2:9A:A8:1C:CB:0B".parse().unwrap();
+        let ess: HashSet<_> = std::iter::once(BOARD_SERVICE_UUID).collect();
+        let none = HashSet::new();
+
+        let included = DiscoveryFilter {
+            include: vec![a],
+            ..filter()
+        };
+        assert!(included.enabled());
+        assert!(included.adopts(a, &none, None));
+        assert!(!included.adopts(b, &ess, Some("eclipse-iot")));
+
+        let either = DiscoveryFilter {
+            service: true,
+            name: Some(Regex::new("^eclipse-iot").unwrap()),
+            exclude: vec![b],
+            ..filter()
+        };
+        assert!(either.adopts(a, &ess, None));
+        assert!(either.adopts(a, &none, Some("eclipse-iot-1")));
+        assert!(!either.adopts(a, &none, Some("other")));
+        assert!(!either.adopts(b, &ess, Some("eclipse-iot-2")));
+    }
+}
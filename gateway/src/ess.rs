@@ -0,0 +1,209 @@
+//! Descriptors of the Environmental Sensing Service, describing how and when a sensor
+//! characteristic is measured and notified.
+
+use serde::Serialize;
+
+pub const MEASUREMENT_DESCRIPTOR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x0000290c00001000800000805f9b34fb);
+pub const TRIGGER_SETTING_DESCRIPTOR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x0000290d00001000800000805f9b34fb);
+
+const SAMPLING_FUNCTIONS: [&str; 8] = [
+    "unspecified",
+    "instantaneous",
+    "arithmetic_mean",
+    "rms",
+    "maximum",
+    "minimum",
+    "accumulated",
+    "count",
+];
+
+const APPLICATIONS: [&str; 34] = [
+    "unspecified",
+    "air",
+    "water",
+    "barometric",
+    "soil",
+    "infrared",
+    "map_database",
+    "barometric_elevation_source",
+    "gps_only_elevation_source",
+    "gps_and_map_database_elevation_source",
+    "vertical_datum_elevation_source",
+    "onshore",
+    "onboard_vessel_or_vehicle",
+    "front",
+    "back",
+    "upper",
+    "lower",
+    "primary",
+    "secondary",
+    "outdoor",
+    "indoor",
+    "top",
+    "bottom",
+    "main",
+    "backup",
+    "auxiliary",
+    "supplementary",
+    "inside",
+    "outside",
+    "left",
+    "right",
+    "internal",
+    "external",
+    "solar",
+];
+
+/// Contents of the ES Measurement descriptor. Periods and intervals are in seconds, and
+/// left out if the sensor doesn't report them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MeasurementDescriptor {
+    pub sampling_function: &'static str,
+    pub measurement_period: Option<u32>,
+    pub update_interval: Option<u32>,
+    pub application: &'static str,
+    /// Uncertainty of the measurement as reported by the sensor.
+    pub uncertainty: Option<u8>,
+}
+
+fn u24(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], 0])
+}
+
+fn name(names: &[&'static str], value: u8) -> &'static str {
+    names.get(value as usize).copied().unwrap_or("reserved")
+}
+
+impl MeasurementDescriptor {
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 11 {
+            return Err(format!("expected 11 bytes, got {}", data.len()));
+        }
+        let seconds = |s| Some(s).filter(|s| *s != 0);
+        Ok(Self {
+            // data[0..2] are flags, which are reserved
+            sampling_function: name(&SAMPLING_FUNCTIONS, data[2]),
+            measurement_period: seconds(u24(&data[3..6])),
+            update_interval: seconds(u24(&data[6..9])),
+            application: name(&APPLICATIONS, data[9]),
+            // 0xFF means the uncertainty is unknown
+            uncertainty: Some(data[10]).filter(|u| *u != 0xff),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerCondition {
+    Inactive,
+    FixedInterval,
+    MinInterval,
+    ValueChanged,
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+/// Contents of an ES Trigger Setting descriptor, telling when the sensor sends notifications.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TriggerSetting {
+    pub condition: TriggerCondition,
+    /// Seconds between notifications, for the interval conditions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u32>,
+    /// Raw value compared against, for the value conditions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operand: Option<Vec<u8>>,
+}
+
+impl TriggerSetting {
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        use TriggerCondition::*;
+        let (condition, operand) = data.split_first().ok_or("empty trigger setting")?;
+        let condition = match condition {
+            0x00 => Inactive,
+            0x01 => FixedInterval,
+            0x02 => MinInterval,
+            0x03 => ValueChanged,
+            0x04 => LessThan,
+            0x05 => LessOrEqual,
+            0x06 => GreaterThan,
+            0x07 => GreaterOrEqual,
+            0x08 => Equal,
+            0x09 => NotEqual,
+            other => return Err(format!("unknown trigger condition {:#04x}", other)),
+        };
+        let mut setting = Self {
+            condition,
+            interval: None,
+            operand: None,
+        };
+        match condition {
+            Inactive | ValueChanged => {}
+            FixedInterval | MinInterval => {
+                if operand.len() < 3 {
+                    return Err(format!("expected 3 byte interval, got {}", operand.len()));
+                }
+                setting.interval = Some(u24(operand));
+            }
+            _ => setting.operand = Some(operand.to_vec()),
+        }
+        Ok(setting)
+    }
+}
+
+/// How often a sensor is expected to report, based on its descriptors.
+pub fn expected_interval(
+    measurement: Option<&MeasurementDescriptor>,
+    triggers: &[TriggerSetting],
+) -> Option<u32> {
+    triggers
+        .iter()
+        .filter(|t| t.condition == TriggerCondition::FixedInterval)
+        .find_map(|t| t.interval)
+        .or_else(|| measurement.and_then(|m| m.update_interval))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_measurement_descriptor() {
+        // As configured by the board firmware
+        let descriptor =
+            MeasurementDescriptor::decode(&[0, 0, 2, 0, 0, 0, 5, 0, 0, 1, 0xff]).unwrap();
+
+        assert_eq!(
+            descriptor,
+            MeasurementDescriptor {
+                sampling_function: "arithmetic_mean",
+                measurement_period: None,
+                update_interval: Some(5),
+                application: "air",
+                uncertainty: None,
+            }
+        );
+        assert!(MeasurementDescriptor::decode(&[0, 0, 2]).is_err());
+    }
+
+    #[test]
+    fn decodes_trigger_settings() {
+        let fixed = TriggerSetting::decode(&[0x01, 5, 0, 0]).unwrap();
+        assert_eq!(fixed.condition, TriggerCondition::FixedInterval);
+        assert_eq!(fixed.interval, Some(5));
+
+        let above = TriggerSetting::decode(&[0x06, 0xfa, 0x00]).unwrap();
+        assert_eq!(above.condition, TriggerCondition::GreaterThan);
+        assert_eq!(above.operand, Some(vec![0xfa, 0x00]));
+
+        assert!(TriggerSetting::decode(&[0x01, 5]).is_err());
+        assert!(TriggerSetting::decode(&[0x42]).is_err());
+        assert_eq!(expected_interval(None, &[fixed]), Some(5));
+    }
+}
@@ -0,0 +1,312 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When to publish changes of a single field, such as temperature.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldFilter {
+    /// Only publish when the value changed by more than this since it was last published.
+    pub deadband: Option<f64>,
+    /// Publish at least this often, even if the value didn't change.
+    #[serde(with = "humantime_serde")]
+    pub heartbeat: Option<Duration>,
+    /// Publish at most this often. The latest value held back is published once the interval
+    /// is over.
+    #[serde(with = "humantime_serde")]
+    pub min_interval: Option<Duration>,
+}
+
+impl FieldFilter {
+    fn validate(&self, field: &str) -> anyhow::Result<()> {
+        if matches!(self.deadband, Some(d) if d < 0.0) {
+            anyhow::bail!("filters.{}.deadband must not be negative", field);
+        }
+        if let (Some(heartbeat), Some(min_interval)) = (self.heartbeat, self.min_interval) {
+            if heartbeat < min_interval {
+                anyhow::bail!(
+                    "filters.{}.heartbeat must not be shorter than filters.{}.min_interval",
+                    field,
+                    field
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn admits(&self, now: Instant, value: f64, last: Option<&Published>) -> Admission {
+        let last = match last {
+            Some(last) => last,
+            None => return Admission::Publish,
+        };
+        let elapsed = now.duration_since(last.at);
+        if matches!(self.heartbeat, Some(heartbeat) if elapsed >= heartbeat) {
+            return Admission::Publish;
+        }
+        let changed = match self.deadband {
+            Some(deadband) => (value - last.value).abs() > deadband,
+            None => true,
+        };
+        match self.min_interval {
+            _ if !changed => Admission::Drop,
+            Some(min) if elapsed < min => Admission::Hold(last.at + min),
+            _ => Admission::Publish,
+        }
+    }
+}
+
+/// What to do with the fields just updated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    Publish,
+    /// Worth publishing, but not before the given time.
+    Hold(Instant),
+    Drop,
+}
+
+/// A message held back until the filters let it through.
+pub struct Held {
+    pub message: serde_json::Value,
+    pub updated: serde_json::Value,
+    /// When the values were received.
+    pub time: DateTime<Utc>,
+    due: Instant,
+}
+
+/// Filters by field name.
+pub type FilterConfig = BTreeMap<String, FieldFilter>;
+
+pub fn validate(config: &FilterConfig) -> anyhow::Result<()> {
+    config
+        .iter()
+        .try_for_each(|(field, filter)| filter.validate(field))
+}
+
+struct Published {
+    value: f64,
+    at: Instant,
+}
+
+/// Decides which readings of a device are published.
+pub struct Filters {
+    config: FilterConfig,
+    published: HashMap<String, Published>,
+    held: Option<Held>,
+}
+
+/// Numeric value of a field, as decoded or aggregated.
+fn value(field: &serde_json::Value) -> Option<f64> {
+    field["value"].as_f64().or_else(|| field["mean"].as_f64())
+}
+
+impl Filters {
+    pub fn new(config: FilterConfig) -> Self {
+        Self {
+            config,
+            published: HashMap::new(),
+            held: None,
+        }
+    }
+
+    /// Whether the fields just updated are worth publishing, now or once the fields that
+    /// changed may be published again. Fields without a filter are always published.
+    pub fn admit(&self, now: Instant, updated: &serde_json::Value) -> Admission {
+        updated.as_object().into_iter().flatten().fold(
+            Admission::Drop,
+            |admission, (name, field)| {
+                let field = match (self.config.get(name), value(field)) {
+                    (Some(filter), Some(value)) => {
+                        filter.admits(now, value, self.published.get(name))
+                    }
+                    _ => Admission::Publish,
+                };
+                match (admission, field) {
+                    (Admission::Publish, _) | (_, Admission::Publish) => Admission::Publish,
+                    (Admission::Hold(a), Admission::Hold(b)) => Admission::Hold(a.min(b)),
+                    (Admission::Hold(due), _) | (_, Admission::Hold(due)) => Admission::Hold(due),
+                    _ => Admission::Drop,
+                }
+            },
+        )
+    }
+
+    /// Holds back a message until `due`. Returns whether it replaced one held so far, which
+    /// is then never published.
+    pub fn hold(
+        &mut self,
+        due: Instant,
+        message: &serde_json::Value,
+        updated: &serde_json::Value,
+        time: DateTime<Utc>,
+    ) -> bool {
+        self.held
+            .replace(Held {
+                message: message.clone(),
+                updated: updated.clone(),
+                time,
+                due,
+            })
+            .is_some()
+    }
+
+    /// Brings the message held back up to date with values that are not worth publishing on
+    /// their own, so it doesn't publish stale ones.
+    pub fn refresh(&mut self, message: &serde_json::Value, time: DateTime<Utc>) {
+        if let Some(held) = &mut self.held {
+            held.message = message.clone();
+            held.time = time;
+        }
+    }
+
+    /// When the message held back is due.
+    pub fn due(&self) -> Option<Instant> {
+        self.held.as_ref().map(|held| held.due)
+    }
+
+    /// The message held back, if it is due.
+    pub fn take_due(&mut self, now: Instant) -> Option<Held> {
+        match &self.held {
+            Some(held) if held.due <= now => self.held.take(),
+            _ => None,
+        }
+    }
+
+    /// Records the values of a message that was published, which supersedes any message
+    /// held back.
+    pub fn published(&mut self, now: Instant, message: &serde_json::Value) {
+        self.held = None;
+        for (name, field) in message.as_object().into_iter().flatten() {
+            if let Some(value) = value(field).filter(|_| self.config.contains_key(name)) {
+                self.published
+                    .insert(name.clone(), Published { value, at: now });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn celsius(value: f64) -> serde_json::Value {
+        json!({ "temperature": { "value": value, "unit": "°C" } })
+    }
+
+    fn filters(filter: FieldFilter) -> Filters {
+        let mut config = FilterConfig::new();
+        config.insert("temperature".to_string(), filter);
+        Filters::new(config)
+    }
+
+    /// Offers the readings at the given seconds and returns the ones published.
+    fn run(filters: &mut Filters, readings: &[(u64, f64)]) -> Vec<u64> {
+        let start = Instant::now();
+        let mut published = Vec::new();
+        for (secs, value) in readings {
+            let now = start + Duration::from_secs(*secs);
+            if filters.admit(now, &celsius(*value)) == Admission::Publish {
+                filters.published(now, &celsius(*value));
+                published.push(*secs);
+            }
+        }
+        published
+    }
+
+    #[test]
+    fn deadband_suppresses_small_changes() {
+        let mut f = filters(FieldFilter {
+            deadband: Some(0.5),
+            ..Default::default()
+        });
+        let readings = [(0, 21.0), (10, 21.2), (20, 21.4), (30, 21.6), (40, 21.7)];
+
+        assert_eq!(run(&mut f, &readings), vec![0, 30]);
+    }
+
+    #[test]
+    fn heartbeat_publishes_unchanged_values() {
+        let mut f = filters(FieldFilter {
+            deadband: Some(0.5),
+            heartbeat: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        let readings: Vec<(u64, f64)> = (0..=12).map(|i| (i * 10, 21.0)).collect();
+
+        assert_eq!(run(&mut f, &readings), vec![0, 60, 120]);
+    }
+
+    #[test]
+    fn min_interval_limits_rate() {
+        let mut f = filters(FieldFilter {
+            min_interval: Some(Duration::from_secs(30)),
+            ..Default::default()
+        });
+        let readings: Vec<(u64, f64)> = (0..7).map(|i| (i * 10, i as f64)).collect();
+
+        assert_eq!(run(&mut f, &readings), vec![0, 30, 60]);
+    }
+
+    #[test]
+    fn min_interval_holds_latest_value() {
+        let mut f = filters(FieldFilter {
+            deadband: Some(0.5),
+            min_interval: Some(Duration::from_secs(30)),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let time = Utc::now();
+        f.published(at(0), &celsius(21.0));
+
+        assert_eq!(f.admit(at(10), &celsius(21.2)), Admission::Drop);
+        assert_eq!(f.admit(at(10), &celsius(22.0)), Admission::Hold(at(30)));
+        assert!(!f.hold(at(30), &celsius(22.0), &celsius(22.0), time));
+        assert!(f.hold(at(30), &celsius(23.0), &celsius(23.0), time));
+
+        assert_eq!(f.due(), Some(at(30)));
+        assert!(f.take_due(at(20)).is_none());
+        let held = f.take_due(at(30)).unwrap();
+        assert_eq!(held.message, celsius(23.0));
+        assert_eq!(held.time, time);
+        assert_eq!(f.admit(at(30), &held.updated), Admission::Publish);
+        assert_eq!(f.due(), None);
+    }
+
+    #[test]
+    fn held_message_keeps_latest_values() {
+        let mut f = filters(FieldFilter {
+            deadband: Some(0.5),
+            min_interval: Some(Duration::from_secs(30)),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let (first, later) = (Utc::now(), Utc::now() + chrono::Duration::seconds(10));
+        f.published(start, &celsius(21.0));
+        f.hold(start, &celsius(22.0), &celsius(22.0), first);
+
+        f.refresh(&celsius(21.9), later);
+
+        let held = f.take_due(start).unwrap();
+        assert_eq!(held.message, celsius(21.9));
+        assert_eq!(held.time, later);
+    }
+
+    #[test]
+    fn unfiltered_fields_are_published() {
+        let f = filters(FieldFilter {
+            deadband: Some(10.0),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            f.admit(
+                Instant::now(),
+                &json!({ "battery": { "value": 90, "unit": "%" } })
+            ),
+            Admission::Publish
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use async_trait::async_trait;
+use bluer::{gatt::remote::Characteristic, Adapter, Address, Device};
+use core::pin::Pin;
+use futures::Stream;
+use std::sync::Arc;
+
+pub type Notifications = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+
+/// Client side of a GATT connection to a single peer.
+#[async_trait]
+pub trait GattClient: Send {
+    type Characteristic: Send + Sync;
+
+    async fn connect(&mut self) -> bluer::Result<()>;
+    async fn disconnect(&mut self) -> bluer::Result<()>;
+    async fn is_connected(&mut self) -> bluer::Result<bool>;
+
+    async fn discover_services(&mut self) -> bluer::Result<Vec<uuid::Uuid>>;
+    async fn find_characteristic(
+        &mut self,
+        service: uuid::Uuid,
+        characteristic: uuid::Uuid,
+    ) -> bluer::Result<Option<Self::Characteristic>>;
+
+    async fn read(&mut self, c: &Self::Characteristic) -> bluer::Result<Vec<u8>>;
+    async fn write(&mut self, c: &Self::Characteristic, value: &[u8]) -> bluer::Result<()>;
+    async fn notify(&mut self, c: &Self::Characteristic) -> bluer::Result<Notifications>;
+
+    /// Values of all descriptors of the characteristic with the given UUID, in the order the
+    /// peer lists them.
+    async fn read_descriptors(
+        &mut self,
+        c: &Self::Characteristic,
+        descriptor: uuid::Uuid,
+    ) -> bluer::Result<Vec<Vec<u8>>>;
+}
+
+/// GATT client using BlueZ.
+pub struct BluerClient {
+    adapter: Arc<Adapter>,
+    address: Address,
+}
+
+impl BluerClient {
+    pub fn new(address: Address, adapter: Arc<Adapter>) -> Self {
+        Self { adapter, address }
+    }
+
+    fn device(&self) -> bluer::Result<Device> {
+        self.adapter.device(self.address)
+    }
+}
+
+#[async_trait]
+impl GattClient for BluerClient {
+    type Characteristic = Characteristic;
+
+    async fn connect(&mut self) -> bluer::Result<()> {
+        self.device()?.connect().await
+    }
+
+    async fn disconnect(&mut self) -> bluer::Result<()> {
+        self.device()?.disconnect().await
+    }
+
+    async fn is_connected(&mut self) -> bluer::Result<bool> {
+        self.device()?.is_connected().await
+    }
+
+    async fn discover_services(&mut self) -> bluer::Result<Vec<uuid::Uuid>> {
+        let mut uuids = Vec::new();
+        for s in self.device()?.services().await? {
+            uuids.push(s.uuid().await?);
+        }
+        Ok(uuids)
+    }
+
+    async fn find_characteristic(
+        &mut self,
+        service: uuid::Uuid,
+        characteristic: uuid::Uuid,
+    ) -> bluer::Result<Option<Characteristic>> {
+        for s in self.device()?.services().await? {
+            if s.uuid().await? == service {
+                for c in s.characteristics().await? {
+                    if c.uuid().await? == characteristic {
+                        return Ok(Some(c));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn read(&mut self, c: &Characteristic) -> bluer::Result<Vec<u8>> {
+        c.read().await
+    }
+
+    async fn write(&mut self, c: &Characteristic, value: &[u8]) -> bluer::Result<()> {
+        c.write(value).await
+    }
+
+    async fn notify(&mut self, c: &Characteristic) -> bluer::Result<Notifications> {
+        Ok(Box::pin(c.notify().await?))
+    }
+
+    async fn read_descriptors(
+        &mut self,
+        c: &Characteristic,
+        descriptor: uuid::Uuid,
+    ) -> bluer::Result<Vec<Vec<u8>>> {
+        let mut values = Vec::new();
+        for d in c.descriptors().await? {
+            if d.uuid().await? == descriptor {
+                values.push(d.read().await?);
+            }
+        }
+        Ok(values)
+    }
+}
@@ -0,0 +1,303 @@
+use crate::cloudevents::Events;
+use crate::commands::Command;
+use core::fmt;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{StatusCode, Url};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+pub struct HttpConfig {
+    pub url: String,
+    pub application: String,
+    pub device: String,
+    pub channel: String,
+    pub password: Option<String>,
+    pub timeout: Duration,
+    pub retries: usize,
+    /// How long the endpoint may wait for a command to return with each publish.
+    pub command_timeout: Option<Duration>,
+    /// Send readings as binary mode CloudEvents.
+    pub events: Option<Events>,
+    /// Readings are already wrapped in structured mode CloudEvents.
+    pub structured: bool,
+}
+
+/// Publishes telemetry to the Drogue Cloud HTTP endpoint.
+pub struct HttpPublisher {
+    client: reqwest::Client,
+    url: Url,
+    device: String,
+    username: String,
+    password: Option<String>,
+    retries: usize,
+    command_timeout: Option<Duration>,
+    events: Option<Events>,
+    structured: bool,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+#[derive(Debug)]
+pub enum PublishError {
+    /// The endpoint responded, but refused the message. Retrying will not help.
+    Rejected { status: StatusCode, body: String },
+    /// The endpoint could not be reached or failed to process the message.
+    Unavailable(String),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected { status, body } => {
+                write!(f, "endpoint rejected message ({}): {}", status, body)
+            }
+            Self::Unavailable(e) => write!(f, "endpoint unavailable: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+impl HttpPublisher {
+    pub fn new(
+        config: HttpConfig,
+        commands: mpsc::UnboundedSender<Command>,
+    ) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.url)?.join(&format!("v1/{}", config.channel))?;
+        let timeout = config.timeout + config.command_timeout.unwrap_or_default();
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self {
+            client,
+            url,
+            username: format!("{}@{}", config.device, config.application),
+            device: config.device,
+            password: config.password,
+            retries: config.retries,
+            command_timeout: config.command_timeout,
+            events: config.events,
+            structured: config.structured,
+            commands,
+        })
+    }
+
+    /// Publishes the payload on behalf of `device`. If it differs from the authenticated
+    /// device, the cloud must have it configured to accept this device as its gateway.
+    pub async fn publish(
+        &self,
+        device: &str,
+        payload: &serde_json::Value,
+    ) -> Result<(), PublishError> {
+        let mut attempt = 0;
+        loop {
+            match self.send(device, payload).await {
+                Err(PublishError::Unavailable(e)) if attempt < self.retries => {
+                    attempt += 1;
+                    let delay = Duration::from_millis(500 * (1 << attempt.min(5)));
+                    log::warn!(
+                        "Error publishing to {} (attempt {}/{}), retrying in {:?}: {}",
+                        self.url,
+                        attempt,
+                        self.retries,
+                        delay,
+                        e
+                    );
+                    sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send(&self, device: &str, payload: &serde_json::Value) -> Result<(), PublishError> {
+        let mut request = self.client.post(self.url.clone());
+        if device != self.device {
+            request = request.query(&[("as", device)]);
+        }
+        if let Some(ct) = self.command_timeout {
+            request = request.query(&[("ct", ct.as_secs())]);
+        }
+        if let Some(events) = &self.events {
+            for (name, value) in events.headers(device, payload) {
+                request = request.header(name, value);
+            }
+        }
+        request = if self.structured {
+            request
+                .header(CONTENT_TYPE, "application/cloudevents+json")
+                .body(payload.to_string())
+        } else {
+            request.json(payload)
+        };
+        let response = request
+            .basic_auth(&self.username, self.password.as_ref())
+            .send()
+            .await
+            .map_err(|e| PublishError::Unavailable(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            log::trace!("Published to {}: {}", self.url, status);
+            let name = response
+                .headers()
+                .get("command")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string());
+            if let Some(name) = name {
+                let payload = response.bytes().await.unwrap_or_default().to_vec();
+                let _ = self.commands.send(Command {
+                    device: device.to_string(),
+                    name,
+                    payload,
+                });
+            }
+            return Ok(());
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(PublishError::Unavailable(format!("{}: {}", status, body)))
+        } else {
+            Err(PublishError::Rejected { status, body })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, HeaderMap, Request, Response, Server};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    /// A request as received by the endpoint.
+    struct Received {
+        uri: String,
+        headers: HeaderMap,
+        body: serde_json::Value,
+    }
+
+    /// Serves an endpoint responding with the given statuses in turn, repeating the last one.
+    /// Returns its URL and the requests it received.
+    fn endpoint(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let requests = received.clone();
+        let make_svc = make_service_fn(move |_| {
+            let requests = requests.clone();
+            let statuses = statuses.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let requests = requests.clone();
+                    let statuses = statuses.clone();
+                    async move {
+                        let uri = req.uri().to_string();
+                        let headers = req.headers().clone();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let mut requests = requests.lock().unwrap();
+                        let status = statuses[requests.len().min(statuses.len() - 1)];
+                        requests.push(Received {
+                            uri,
+                            headers,
+                            body: serde_json::from_slice(&body).unwrap(),
+                        });
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        (url, received)
+    }
+
+    fn config(url: String, retries: usize) -> HttpConfig {
+        HttpConfig {
+            url,
+            application: "app".to_string(),
+            device: "gateway".to_string(),
+            channel: "telemetry".to_string(),
+            password: Some("secret".to_string()),
+            timeout: Duration::from_secs(5),
+            retries,
+            command_timeout: None,
+            events: None,
+            structured: false,
+        }
+    }
+
+    fn publisher(url: String, retries: usize) -> HttpPublisher {
+        let (commands, _) = mpsc::unbounded_channel();
+        HttpPublisher::new(config(url, retries), commands).unwrap()
+    }
+
+    #[tokio::test]
+    async fn publishes_on_behalf_of_devices() {
+        let (url, received) = endpoint(vec![202]);
+        let payload = json!({ "temperature": { "value": 21.5, "unit": "°C" } });
+
+        publisher(url, 0)
+            .publish("kitchen", &payload)
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].uri, "/v1/telemetry?as=kitchen");
+        assert_eq!(
+            received[0].headers["authorization"],
+            "Basic Z2F0ZXdheUBhcHA6c2VjcmV0"
+        );
+        assert_eq!(received[0].headers["content-type"], "application/json");
+        assert_eq!(received[0].body, payload);
+    }
+
+    #[tokio::test]
+    async fn sends_structured_events_as_cloudevents() {
+        let (url, received) = endpoint(vec![202]);
+        let (commands, _) = mpsc::unbounded_channel();
+        let publisher = HttpPublisher::new(
+            HttpConfig {
+                structured: true,
+                ..config(url, 0)
+            },
+            commands,
+        )
+        .unwrap();
+        let event = json!({ "specversion": "1.0", "id": "1", "data": {} });
+
+        publisher.publish("gateway", &event).await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(
+            received[0].headers["content-type"],
+            "application/cloudevents+json"
+        );
+        assert_eq!(received[0].body, event);
+    }
+
+    #[tokio::test]
+    async fn retries_only_when_unavailable() {
+        let (url, received) = endpoint(vec![503, 202]);
+        publisher(url, 1)
+            .publish("gateway", &json!({}))
+            .await
+            .unwrap();
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert_eq!(received.lock().unwrap()[0].uri, "/v1/telemetry");
+
+        let (url, received) = endpoint(vec![403]);
+        let result = publisher(url, 3).publish("gateway", &json!({})).await;
+        assert!(matches!(
+            result,
+            Err(PublishError::Rejected { status, .. }) if status == StatusCode::FORBIDDEN
+        ));
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+}
@@ -1,30 +1,226 @@
+use crate::aggregate::{AggregationConfig, Window};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use clap::Parser;
-use core::str::FromStr;
-use futures::future::{select, Either};
 use futures::{pin_mut, StreamExt};
 use serde_json::json;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
 
+mod adapter;
+mod aggregate;
+mod api;
 mod board;
+mod cloudevents;
+mod commands;
+mod config;
+mod decoders;
+mod discovery;
+mod ess;
+mod filter;
+mod gatt;
+mod http;
+mod metrics;
+mod mock;
+mod mqtt;
+mod queue;
+mod reconnect;
+mod recording;
+mod simulator;
+mod sink;
+mod systemd;
 
-use crate::board::Microbit;
+use crate::adapter::SharedAdapter;
+use crate::api::DeviceStates;
+use crate::board::{BoardError, Microbit};
+use crate::cloudevents::{EventFormat, Events};
+use crate::commands::{DeviceCommand, Dispatcher};
+use crate::config::{Args, Config, DeviceSpec, Secret};
+use crate::decoders::{DecodingConfig, Registry};
+use crate::filter::{Admission, FilterConfig, Filters};
+use crate::gatt::GattClient;
+use crate::http::{HttpConfig, HttpPublisher};
+use crate::mock::MockPeer;
+use crate::mqtt::{MqttConfig, MqttPublisher};
+use crate::queue::QueuedSink;
+use crate::reconnect::{Backoff, ReconnectPolicy, Retry};
+use crate::recording::Recorder;
+use crate::sink::Sink;
+use crate::systemd::{Heartbeat, Heartbeats, Notifier, HEARTBEAT_INTERVAL};
 
-#[derive(Parser, Debug)]
-struct Args {
-    #[clap(short, long, parse(from_occurrences))]
-    verbose: usize,
+/// Firmware that devices should be running.
+struct Firmware {
+    version: String,
+    data: Vec<u8>,
+}
+
+/// State shared by all device tasks.
+struct Gateway {
+    id: String,
+    /// Identifies this run of the gateway, as sequence numbers start over with every run.
+    boot_id: uuid::Uuid,
+    sink: Sink,
+    report_interval: u8,
+    firmware: Option<Firmware>,
+    reconnect: ReconnectPolicy,
+    aggregation: AggregationConfig,
+    filters: FilterConfig,
+    decoders: Arc<Registry>,
+    decoding: DecodingConfig,
+    recorder: Option<Arc<Recorder>>,
+    commands: Dispatcher,
+    states: Arc<DeviceStates>,
+    sequences: Mutex<HashMap<String, u64>>,
+    /// Set once the gateway is asked to shut down.
+    shutdown: watch::Receiver<bool>,
+    systemd: Notifier,
+    /// Beaten by the discovery loop and each device task as they make progress.
+    heartbeats: Arc<Heartbeats>,
+}
+
+impl Gateway {
+    fn stopping(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Completes once the gateway is asked to shut down.
+    async fn stopped(&self) {
+        let mut shutdown = self.shutdown.clone();
+        while !*shutdown.borrow_and_update() {
+            if shutdown.changed().await.is_err() {
+                // Nobody can ask for a shutdown anymore
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Publishes a message for a device, stamped with the time it was produced, the
+    /// gateway id and a per-device sequence number, so consumers can order messages and
+    /// detect gaps. Sequence numbers restart with the boot id when the gateway restarts.
+    /// The message id stays the same when it is sent again, so consumers can drop duplicates.
+    async fn publish(
+        &self,
+        device: &str,
+        payload: &serde_json::Value,
+        time: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let seq = {
+            let mut sequences = self.sequences.lock().unwrap();
+            let seq = sequences.entry(device.to_string()).or_insert(0);
+            *seq += 1;
+            *seq
+        };
+        let mut message = payload.clone();
+        message["timestamp"] = json!(time);
+        message["seq"] = json!(seq);
+        message["boot"] = json!(self.boot_id);
+        message["gateway"] = json!(self.id);
+        message["id"] = json!(uuid::Uuid::new_v4());
+        self.sink.publish(device, &message).await
+    }
+
+    /// Merges a decoded reading, received at `time`, into the state of a device.
+    fn update(
+        &self,
+        device: &str,
+        address: &str,
+        view: &mut serde_json::Value,
+        reading: &serde_json::Value,
+        time: DateTime<Utc>,
+    ) {
+        merge(view, reading);
+        self.states.update(device, address, |s| {
+            s.last_seen = Some(time);
+            s.state = view.clone();
+        });
+    }
+
+    /// Merges a decoded reading into the state of a device and publishes the result, if the
+    /// filters let it through.
+    async fn reading(
+        &self,
+        device: &str,
+        address: &str,
+        view: &mut serde_json::Value,
+        reading: &serde_json::Value,
+        time: DateTime<Utc>,
+        filters: &mut Filters,
+    ) {
+        self.update(device, address, view, reading, time);
+        self.telemetry(device, view, reading, time, filters).await;
+    }
 
-    #[clap(short, long)]
-    device: String,
+    /// Publishes telemetry of a device, if the fields that were `updated` pass the filters.
+    /// Telemetry that may only be published later is held back until `flush` is called.
+    async fn telemetry(
+        &self,
+        device: &str,
+        message: &serde_json::Value,
+        updated: &serde_json::Value,
+        time: DateTime<Utc>,
+        filters: &mut Filters,
+    ) {
+        let now = tokio::time::Instant::now();
+        match filters.admit(now, updated) {
+            Admission::Publish => {}
+            Admission::Hold(due) => {
+                log::trace!("Holding back {} {}", device, updated);
+                if filters.hold(due, message, updated, time) {
+                    metrics::FILTERED.with_label_values(&[device]).inc();
+                }
+                return;
+            }
+            Admission::Drop => {
+                log::trace!("Not publishing {} {}", device, updated);
+                metrics::FILTERED.with_label_values(&[device]).inc();
+                filters.refresh(message, time);
+                return;
+            }
+        }
+        match self.publish(device, message, time).await {
+            Ok(()) => filters.published(now, message),
+            Err(e) => log::warn!("Error publishing telemetry for {}: {}", device, e),
+        }
+    }
 
-    #[clap(short, long, parse(try_from_str=humantime::parse_duration))]
-    report_interval: Duration,
+    /// Publishes the statistics of the readings within the window of a device, if there
+    /// were any.
+    async fn summarize(
+        &self,
+        device: &str,
+        view: &serde_json::Value,
+        window: &mut Window,
+        time: DateTime<Utc>,
+        filters: &mut Filters,
+    ) {
+        if let Some(summary) = window.summary(tokio::time::Instant::now()) {
+            // Statistics replace the latest values, other state such as device information is
+            // published as is
+            let mut message = view.clone();
+            for (name, stats) in summary.as_object().into_iter().flatten() {
+                message[name] = stats.clone();
+            }
+            self.telemetry(device, &message, &summary, time, filters)
+                .await;
+        }
+    }
+
+    /// Publishes the telemetry the filters held back, once it is due.
+    async fn flush(&self, device: &str, filters: &mut Filters) {
+        if let Some(held) = filters.take_due(tokio::time::Instant::now()) {
+            self.telemetry(device, &held.message, &held.updated, held.time, filters)
+                .await;
+        }
+    }
 }
 
 fn merge(a: &mut serde_json::Value, b: &serde_json::Value) {
     match (a, b) {
-        (&mut serde_json::Value::Object(ref mut a), &serde_json::Value::Object(ref b)) => {
+        (&mut serde_json::Value::Object(ref mut a), serde_json::Value::Object(b)) => {
             for (k, v) in b {
                 merge(a.entry(k.clone()).or_insert(serde_json::Value::Null), v);
             }
@@ -38,59 +234,1337 @@ fn merge(a: &mut serde_json::Value, b: &serde_json::Value) {
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    stderrlog::new().verbosity(args.verbose).init().unwrap();
-    let device = args.device;
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let print_config = args.print_config;
+    config.apply(args);
+    config.validate()?;
+    if print_config {
+        print!("{}", toml::to_string_pretty(&config)?);
+        return Ok(());
+    }
+
+    stderrlog::new()
+        .verbosity(config.verbosity()?)
+        .init()
+        .unwrap();
+
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    tokio::spawn(handle_signals(shutdown_tx));
 
-    let report_interval = args.report_interval.as_secs().min(255).max(1) as u8;
+    let devices = config.all_devices()?;
+    let filter = config.discovery_filter()?;
+    let report_interval = match config.replay {
+        // Not used, there are no devices to configure
+        Some(_) => 0,
+        None => config.report_interval()?,
+    };
+
+    let states = Arc::new(DeviceStates::default());
+    let events = Events {
+        gateway: config.gateway_id.clone(),
+        event_type: config.events.event_type.clone(),
+        subject: config.event_subject(),
+        dataschema: config.events.dataschema.clone(),
+        states: states.clone(),
+    };
+
+    let (commands_tx, mut commands_rx) = mpsc::unbounded_channel();
+    let sink = if let Some(url) = config.http.url.clone() {
+        let http = &config.http;
+        Sink::Http(HttpPublisher::new(
+            HttpConfig {
+                url,
+                application: http.application.clone().unwrap(),
+                device: http.device.clone().unwrap(),
+                channel: http.channel.clone(),
+                password: http.password.as_ref().map(Secret::resolve).transpose()?,
+                timeout: http.timeout,
+                retries: http.retries,
+                command_timeout: http.command_timeout,
+                events: match config.events.format {
+                    EventFormat::Binary => Some(events.clone()),
+                    _ => None,
+                },
+                structured: config.events.format == EventFormat::Structured,
+            },
+            commands_tx,
+        )?)
+    } else if let Some(host) = config.mqtt.host.clone() {
+        let mqtt = &config.mqtt;
+        Sink::Mqtt(MqttPublisher::new(
+            MqttConfig {
+                host,
+                port: mqtt.port,
+                client_id: mqtt.client_id.clone(),
+                username: mqtt.username.clone(),
+                password: mqtt.password.as_ref().map(Secret::resolve).transpose()?,
+                topic: mqtt.topic.clone(),
+                qos: config.mqtt_qos()?,
+                tls: mqtt.tls,
+                timeout: mqtt.timeout,
+                command_topic: mqtt.command_topic.clone(),
+            },
+            commands_tx,
+        ))
+    } else {
+        Sink::Stdout
+    };
+    let sink = match config.events.format {
+        EventFormat::Structured => Sink::Events(events, Box::new(sink)),
+        _ => sink,
+    };
+    let sink = match &config.queue.dir {
+        Some(dir) => {
+            let queue = Arc::new(QueuedSink::open(dir, config.queue.max, sink)?);
+            let forwarder = queue.clone();
+            tokio::spawn(async move { forwarder.forward().await });
+            Sink::Queued(queue)
+        }
+        None => sink,
+    };
+
+    let firmware = match (&config.firmware.path, &config.firmware.version) {
+        (Some(path), Some(version)) => Some(Firmware {
+            version: version.clone(),
+            data: std::fs::read(path)
+                .with_context(|| format!("reading firmware {}", path.display()))?,
+        }),
+        _ => None,
+    };
+
+    if let Some(addr) = config.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr).await {
+                log::error!("Error serving metrics: {}", e);
+            }
+        });
+    }
+
+    let recorder = match &config.record {
+        Some(path) => {
+            Some(Arc::new(Recorder::create(path).with_context(|| {
+                format!("opening recording {}", path.display())
+            })?))
+        }
+        None => None,
+    };
+
+    let gateway = Arc::new(Gateway {
+        id: config.gateway_id.clone(),
+        boot_id: uuid::Uuid::new_v4(),
+        sink,
+        report_interval,
+        firmware,
+        reconnect: config.reconnect.clone(),
+        aggregation: config.aggregation.clone(),
+        filters: config.filters.clone(),
+        decoders: Arc::new(Registry::default()),
+        decoding: config.decoding.clone(),
+        recorder,
+        commands: Dispatcher::default(),
+        states,
+        sequences: Mutex::new(HashMap::new()),
+        shutdown,
+        systemd: Notifier::new(&config.systemd),
+        heartbeats: Arc::new(Heartbeats::default()),
+    });
+    tokio::spawn(
+        gateway
+            .systemd
+            .supervise(gateway.states.clone(), gateway.heartbeats.clone()),
+    );
+
+    if let Some(addr) = config.api_addr {
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api::serve(addr, gateway).await {
+                log::error!("Error serving device API: {}", e);
+            }
+        });
+    }
+
+    let dispatcher = gateway.clone();
+    tokio::spawn(async move {
+        while let Some(command) = commands_rx.recv().await {
+            log::info!("Received command {} for {}", command.name, command.device);
+            if let Err(e) = dispatcher.commands.dispatch(&command) {
+                log::warn!(
+                    "Rejected command {} for {}: {}",
+                    command.name,
+                    command.device,
+                    e
+                );
+                // Failures for devices the gateway doesn't handle are only logged, publishing
+                // under their names would speak for devices that aren't ours
+                if !dispatcher.commands.knows(&command.device) {
+                    continue;
+                }
+                let ack = commands::ack(&command.name, Err(e));
+                if let Err(e) = dispatcher.publish(&command.device, &ack, Utc::now()).await {
+                    log::warn!("Error publishing command failure: {}", e);
+                }
+            }
+        }
+    });
+
+    if let Some(path) = &config.replay {
+        gateway.systemd.ready();
+        run_replay(path, config.replay_speed, &gateway).await?;
+        return run_until_stopped(&gateway, Vec::new(), config.shutdown_timeout).await;
+    }
+
+    if config.simulator.enabled {
+        let mut devices = devices;
+        if devices.is_empty() {
+            devices.push("00:00:00:00:00:01=simulator".parse()?);
+        }
+        let tasks = devices
+            .into_iter()
+            .map(|spec| {
+                log::info!("Simulating {} ({})", spec.name, spec.address);
+                let peer = simulator::spawn(config.simulator.clone(), report_interval);
+                tokio::spawn(run_device(spec, gateway.clone(), Link::Simulated(peer)))
+            })
+            .collect();
+        gateway.systemd.ready();
+        return run_until_stopped(&gateway, tasks, config.shutdown_timeout).await;
+    }
 
     let session = bluer::Session::new().await?;
-    let adapter = Arc::new(session.default_adapter().await?);
+    let adapter = match &config.adapter {
+        Some(name) => session.adapter(name)?,
+        None => session.default_adapter().await?,
+    };
     adapter.set_powered(true).await?;
-    let address = bluer::Address::from_str(&device)?;
+    let adapter = Arc::new(SharedAdapter::new(
+        adapter,
+        config.reconnect.power_cycle_after,
+    ));
+
+    let configured: HashMap<bluer::Address, DeviceSpec> =
+        devices.into_iter().map(|d| (d.address, d)).collect();
+    let mut pending = configured.clone();
+    let mut adopted = HashSet::new();
+    let mut tasks = Vec::new();
+    // Devices whose task ended, such as after giving up on them, can be adopted again
+    let (ended_tx, mut ended) = mpsc::unbounded_channel();
 
-    let discover = adapter.discover_devices().await?;
-    pin_mut!(discover);
-    while let Some(evt) = discover.next().await {
+    // Devices are reported again whenever their properties change, so a name or service that
+    // only shows up in a later advertisement still gets the device adopted. Discovery keeps
+    // running, so that devices which were removed after failing are found again.
+    let mut discover = Box::pin(adapter.adapter.discover_devices_with_changes().await?);
+    gateway.systemd.ready();
+    let heartbeat = gateway.heartbeats.register("discovery");
+    let mut beats = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        let evt = tokio::select! {
+            evt = discover.next() => evt,
+            _ = beats.tick() => {
+                heartbeat.beat();
+                continue;
+            }
+            Some(a) = ended.recv() => {
+                adopted.remove(&a);
+                if let Some(spec) = configured.get(&a) {
+                    pending.insert(a, spec.clone());
+                }
+                continue;
+            }
+            _ = adapter.power_cycled() => {
+                log::info!("Restarting discovery after power cycling the adapter");
+                discover = Box::pin(
+                    adapter
+                        .adapter
+                        .discover_devices_with_changes()
+                        .await
+                        .context("restarting discovery")?,
+                );
+                continue;
+            }
+            _ = gateway.stopped() => break,
+        };
+        let evt = match evt {
+            Some(evt) => evt,
+            None => break,
+        };
         log::trace!("Discovery event: {:?}", evt);
-        match evt {
-            bluer::AdapterEvent::DeviceAdded(a) => {
-                if a == address {
-                    break;
+        if let bluer::AdapterEvent::DeviceAdded(a) = evt {
+            let spec = if let Some(spec) = pending.remove(&a) {
+                spec
+            } else if !adopted.contains(&a) && filter.enabled() {
+                let matches = match adapter.adapter.device(a) {
+                    Ok(device) => filter.matches(&device).await,
+                    Err(e) => Err(e),
+                };
+                match matches {
+                    Ok(true) => DeviceSpec {
+                        address: a,
+                        name: a.to_string(),
+                    },
+                    Ok(false) => continue,
+                    Err(e) => {
+                        log::warn!("Error inspecting discovered device {}: {}", a, e);
+                        continue;
+                    }
                 }
+            } else {
+                continue;
+            };
+
+            log::info!("Discovered {} ({})", spec.name, spec.address);
+            adopted.insert(a);
+            let device = run_device(spec, gateway.clone(), Link::Ble(adapter.clone()));
+            let ended = ended_tx.clone();
+            tasks.push(tokio::spawn(async move {
+                device.await;
+                let _ = ended.send(a);
+            }));
+        }
+    }
+    drop(heartbeat);
+
+    run_until_stopped(&gateway, tasks, config.shutdown_timeout).await
+}
+
+/// Asks the gateway to shut down on SIGINT or SIGTERM. A second signal exits right away, with
+/// the status of a process killed by that signal.
+async fn handle_signals(shutdown: watch::Sender<bool>) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut received = false;
+    loop {
+        let kind = tokio::select! {
+            _ = interrupt.recv() => SignalKind::interrupt(),
+            _ = terminate.recv() => SignalKind::terminate(),
+        };
+        if received {
+            log::warn!("Exiting without disconnecting devices");
+            std::process::exit(128 + kind.as_raw_value());
+        }
+        log::info!("Shutting down");
+        received = true;
+        let _ = shutdown.send(true);
+    }
+}
+
+/// Waits until the device tasks end or the gateway is asked to shut down. On shutdown, the
+/// devices get `timeout` to disconnect and the uplink to publish pending messages.
+async fn run_until_stopped(
+    gateway: &Gateway,
+    tasks: Vec<JoinHandle<()>>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let tasks = futures::future::join_all(tasks);
+    pin_mut!(tasks);
+    let ended = tokio::select! {
+        _ = &mut tasks => true,
+        _ = gateway.stopped() => false,
+    };
+    gateway.systemd.stopping();
+    let deadline = tokio::time::Instant::now() + timeout;
+    if !ended && tokio::time::timeout_at(deadline, tasks).await.is_err() {
+        log::warn!("Devices did not disconnect within {:?}", timeout);
+    }
+    gateway
+        .sink
+        .flush(deadline)
+        .await
+        .context("publishing pending messages")?;
+    log::info!("Shutdown complete");
+    Ok(())
+}
+
+/// Number of reporting periods without a notification before a device is considered gone.
+const LIVENESS_PERIODS: u32 = 3;
+/// Time allowed on top of a reporting period at least, so short or unknown periods don't
+/// make devices time out right away.
+const LIVENESS_SLACK: Duration = Duration::from_secs(10);
+
+/// The board reports as often as its descriptors say, unless the gateway changed its interval
+/// since. The board doesn't update its descriptors then, so the longer of the two is used.
+fn liveness_timeout<C: gatt::GattClient>(board: &Microbit<C>, interval: u8) -> Duration {
+    let period = ess::expected_interval(board.measurement(), board.triggers())
+        .unwrap_or(0)
+        .max(interval as u32);
+    let period = Duration::from_secs(period as u64);
+    (period * LIVENESS_PERIODS).max(period + LIVENESS_SLACK)
+}
+
+/// How long to wait before retrying a device that lacks the services of a supported board.
+const INCOMPATIBLE_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How long to wait before installing firmware again on a device that doesn't run it after an
+/// update, doubling with each further attempt.
+const FIRMWARE_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+const FIRMWARE_MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Firmware updates attempted on a device, so that a device which doesn't end up running the
+/// new firmware isn't updated again on every reconnect.
+#[derive(Default)]
+struct UpdateAttempts {
+    version: Option<String>,
+    count: u32,
+    last: Option<tokio::time::Instant>,
+    reported: bool,
+}
+
+impl UpdateAttempts {
+    /// Whether to install `version` on device `name`, which currently runs `current`.
+    fn due(&mut self, name: &str, current: &str, version: &str) -> bool {
+        if self.version.as_deref() != Some(version) {
+            *self = Self::default();
+        }
+        if current == version {
+            if self.count > 0 {
+                log::info!("{} now runs firmware {}", name, version);
+                *self = Self::default();
             }
-            _ => {}
+            return false;
         }
+        let last = match self.last {
+            Some(last) => last,
+            None => return true,
+        };
+        if !self.reported {
+            log::error!(
+                "{} still runs firmware {} after updating to {}",
+                name,
+                current,
+                version
+            );
+            self.reported = true;
+        }
+        let delay = FIRMWARE_RETRY_DELAY * (1 << (self.count - 1).min(5));
+        last.elapsed() >= delay.min(FIRMWARE_MAX_RETRY_DELAY)
+    }
+
+    fn attempted(&mut self, version: &str) {
+        self.version = Some(version.to_string());
+        self.count += 1;
+        self.last = Some(tokio::time::Instant::now());
+        self.reported = false;
     }
+}
 
+/// State of a device task that is kept across reconnects, so that a short outage doesn't
+/// cut an aggregation window short or make the filters publish again.
+struct DeviceSession {
+    commands: mpsc::UnboundedReceiver<DeviceCommand>,
+    interval: u8,
+    window: Option<Window>,
+    /// When the window is summarized next, which keeps its pace while reconnecting.
+    summaries: Option<tokio::time::Interval>,
+    filters: Filters,
+    updates: UpdateAttempts,
+    heartbeat: Heartbeat,
+}
+
+/// How a device task reaches its device.
+enum Link {
+    Ble(Arc<SharedAdapter>),
+    Simulated(MockPeer),
+}
+
+impl Link {
+    /// Makes BlueZ forget the device, so it starts from a clean slate when rediscovered.
+    async fn remove_device(&self, address: bluer::Address) {
+        if let Self::Ble(adapter) = self {
+            let _ = adapter.adapter.remove_device(address).await;
+        }
+    }
+
+    fn succeeded(&self, device: &str) {
+        if let Self::Ble(adapter) = self {
+            adapter.failures.succeeded(device);
+        }
+    }
+
+    fn forget(&self, device: &str) {
+        if let Self::Ble(adapter) = self {
+            adapter.failures.forget(device);
+        }
+    }
+
+    /// Records a failure of a device, and power cycles the adapter if all its devices keep
+    /// failing. A single failing device never disturbs the others.
+    async fn failed(&self, device: &str) {
+        if let Self::Ble(adapter) = self {
+            if adapter.failures.failed(device) {
+                log::warn!("All devices keep failing, power cycling adapter");
+                adapter.power_cycle().await;
+            }
+        }
+    }
+}
+
+/// Connects to a single device and publishes its sensor readings, reconnecting when the
+/// device stops reporting.
+async fn run_device(spec: DeviceSpec, gateway: Arc<Gateway>, link: Link) {
+    let window = gateway.aggregation.window();
+    let summaries = window.as_ref().map(|w| {
+        let mut summaries =
+            tokio::time::interval_at(tokio::time::Instant::now() + w.step(), w.step());
+        // Summaries missed while disconnected would only repeat the same readings
+        summaries.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        summaries
+    });
+    let mut session = DeviceSession {
+        commands: gateway.commands.register(&spec.name),
+        interval: gateway.report_interval,
+        window,
+        summaries,
+        filters: Filters::new(gateway.filters.clone()),
+        updates: UpdateAttempts::default(),
+        heartbeat: gateway.heartbeats.register(&spec.name),
+    };
+    let address = spec.address.to_string();
+    gateway
+        .states
+        .update(&spec.name, &address, |s| s.interval = session.interval);
+    let mut backoff = Backoff::new(gateway.reconnect.clone());
+    link.succeeded(&spec.name);
     loop {
-        let mut board = Microbit::new(&device, adapter.clone());
-        board.set_interval(report_interval).await?;
-        let s = board.stream_sensors().await?;
-        pin_mut!(s);
-        let mut view = json!({});
+        metrics::CONNECTION_ATTEMPTS
+            .with_label_values(&[&spec.name])
+            .inc();
+        let mut min_delay = Duration::from_secs(0);
+        let result = match &link {
+            Link::Ble(adapter) => match Microbit::new(&address, adapter.adapter.clone()) {
+                Ok(board) => stream_device(&spec, &gateway, board, &link, &mut session).await,
+                Err(e) => Err(e.into()),
+            },
+            Link::Simulated(peer) => {
+                let board = Microbit::with_client(peer.client());
+                stream_device(&spec, &gateway, board, &link, &mut session).await
+            }
+        };
+        let retry = match result {
+            Ok(()) => {
+                backoff.reset();
+                link.succeeded(&spec.name);
+                Retry::After(gateway.reconnect.initial_delay)
+            }
+            Err(e) => {
+                let reason = match e.downcast_ref::<BoardError>() {
+                    Some(BoardError::InvalidAddress(_)) => {
+                        log::error!("Giving up on {}: {}", spec.name, e);
+                        link.forget(&spec.name);
+                        return;
+                    }
+                    // Not a board we can talk to, or one in the middle of a firmware update
+                    Some(BoardError::ServiceMissing(_))
+                    | Some(BoardError::CharacteristicMissing { .. }) => {
+                        log::error!("{} is not a supported board: {}", spec.name, e);
+                        min_delay = INCOMPATIBLE_RETRY_DELAY;
+                        // The adapter reached the device, so it is not to blame
+                        link.succeeded(&spec.name);
+                        "incompatible"
+                    }
+                    _ => {
+                        log::warn!("Error communicating with {}: {}", spec.name, e);
+                        link.failed(&spec.name).await;
+                        "error"
+                    }
+                };
+                metrics::RECONNECTS
+                    .with_label_values(&[&spec.name, reason])
+                    .inc();
+                backoff.failed()
+            }
+        };
+        metrics::CONNECTED.with_label_values(&[&spec.name]).set(0);
+        metrics::CONSECUTIVE_FAILURES
+            .with_label_values(&[&spec.name])
+            .set(backoff.failures() as i64);
+        gateway
+            .states
+            .update(&spec.name, &address, |s| s.connected = false);
+        log::info!("BLE sensor {} disconnected", spec.name);
+        if gateway.stopping() {
+            return;
+        }
+
+        let delay = match retry {
+            Retry::After(delay) => delay,
+            Retry::GiveUp => {
+                log::error!(
+                    "Giving up on {} after {} failed attempts",
+                    spec.name,
+                    backoff.failures()
+                );
+                metrics::GIVEN_UP.with_label_values(&[&spec.name]).inc();
+                link.forget(&spec.name);
+                return;
+            }
+        };
+        let delay = delay.max(min_delay);
+        log::info!("Reconnecting to {} in {:?}", spec.name, delay);
+        let reconnect = tokio::time::sleep(delay);
+        pin_mut!(reconnect);
+        let mut beats = tokio::time::interval(HEARTBEAT_INTERVAL);
         loop {
-            let timeout = tokio::time::sleep(Duration::from_secs(report_interval as u64 + 10));
-            let next = s.next();
-            pin_mut!(timeout);
-            pin_mut!(next);
-            match select(next, timeout).await {
-                Either::Left((n, _)) => {
-                    if let Some(n) = n {
-                        merge(&mut view, &n);
-                        println!("{}", view);
-                    } else {
-                        log::info!("Event stream closed, removing device");
-                        let _ = adapter.remove_device(address).await;
-                        break;
+            tokio::select! {
+                _ = &mut reconnect => break,
+                _ = beats.tick() => session.heartbeat.beat(),
+                _ = gateway.stopped() => return,
+            }
+        }
+    }
+}
+
+async fn stream_device<C: GattClient>(
+    spec: &DeviceSpec,
+    gateway: &Gateway,
+    board: Microbit<C>,
+    link: &Link,
+    session: &mut DeviceSession,
+) -> anyhow::Result<()> {
+    let DeviceSession {
+        commands,
+        interval,
+        window,
+        summaries,
+        filters,
+        updates,
+        heartbeat,
+    } = session;
+    let address = spec.address.to_string();
+    let mut board = board
+        .with_decoders(gateway.decoders.clone())
+        .with_temperature_exponent(gateway.decoding.temperature_exponent);
+    if let Some(recorder) = &gateway.recorder {
+        board = board.with_recorder(recorder.device(&spec.name, &address));
+    }
+
+    // Updates are done before streaming starts, so they never compete with telemetry
+    if let Some(firmware) = &gateway.firmware {
+        let current = match beating(heartbeat, board.firmware_version()).await {
+            Ok(current) => Some(current),
+            Err(e @ BoardError::ServiceMissing(_)) => {
+                log::warn!("Not updating {}: {}", spec.name, e);
+                None
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(current) = current.filter(|c| updates.due(&spec.name, c, &firmware.version)) {
+            log::info!(
+                "Updating {} from firmware {} to {}",
+                spec.name,
+                current,
+                firmware.version
+            );
+            updates.attempted(&firmware.version);
+            beating(heartbeat, board.update_firmware(&firmware.data)).await?;
+            log::info!(
+                "Firmware update of {} complete, device is resetting",
+                spec.name
+            );
+            link.remove_device(spec.address).await;
+            return Ok(());
+        }
+    }
+
+    // Connecting takes a while, the board connects on the first of these
+    beating(heartbeat, board.set_interval(*interval)).await?;
+    let mut s = beating(heartbeat, board.stream_sensors()).await?;
+    // What the board reports is its initial state, just like the current sensor values
+    *interval = beating(heartbeat, board.interval()).await?;
+    log::debug!("{} reporting every {}s", spec.name, interval);
+    metrics::CONNECTED.with_label_values(&[&spec.name]).set(1);
+    gateway.states.update(&spec.name, &address, |s| {
+        s.connected = true;
+        s.interval = *interval;
+        s.measurement = board.measurement().cloned();
+        s.triggers = board.triggers().to_vec();
+    });
+    let mut view = json!({});
+    if let Some(info) = board.info() {
+        view["device"] = serde_json::to_value(info)?;
+    }
+    let reported = interval_reading(*interval);
+    match window {
+        Some(_) => gateway.update(&spec.name, &address, &mut view, &reported, Utc::now()),
+        None => {
+            let reading = gateway.reading(
+                &spec.name,
+                &address,
+                &mut view,
+                &reported,
+                Utc::now(),
+                filters,
+            );
+            beating(heartbeat, reading).await
+        }
+    }
+    let mut last_seen = tokio::time::Instant::now();
+    let mut beats = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        let timeout = tokio::time::sleep_until(last_seen + liveness_timeout(&board, *interval));
+        let held = until(filters.due());
+        // Publishing happens within the loop and may take longer than the liveness timeout
+        // while the uplink is down. Notifications received in the meantime are handled first,
+        // so the device only times out if it really went quiet.
+        tokio::select! {
+            biased;
+            n = s.next() => {
+                if let Some(n) = n {
+                    let n = match n {
+                        Ok(n) => n,
+                        Err(e) => {
+                            log::warn!("Skipping notification from {}: {}", spec.name, e);
+                            continue;
+                        }
+                    };
+                    metrics::reading_received(&spec.name);
+                    last_seen = tokio::time::Instant::now();
+                    let received = Utc::now();
+                    match window {
+                        Some(window) => {
+                            gateway.update(&spec.name, &address, &mut view, &n, received);
+                            window.add(last_seen, &n);
+                        }
+                        None => {
+                            let reading = gateway
+                                .reading(&spec.name, &address, &mut view, &n, received, filters);
+                            beating(heartbeat, reading).await
+                        }
                     }
+                } else {
+                    log::info!("Event stream for {} closed, removing device", spec.name);
+                    metrics::RECONNECTS
+                        .with_label_values(&[&spec.name, "stream_closed"])
+                        .inc();
+                    link.remove_device(spec.address).await;
+                    return Ok(());
+                }
+            }
+            Some(command) = commands.recv() => {
+                let result = match command {
+                    DeviceCommand::SetInterval(i) => board.set_interval(i).await.map(|_| {
+                        log::info!("{} now reporting every {}s", spec.name, i);
+                        *interval = i;
+                    }),
+                };
+                merge(&mut view, &interval_reading(*interval));
+                gateway.states.update(&spec.name, &address, |s| {
+                    s.interval = *interval;
+                    s.state = view.clone();
+                });
+                let ack = commands::ack(command.name(), result.map_err(|e| e.to_string()));
+                let published = gateway.publish(&spec.name, &ack, Utc::now());
+                if let Err(e) = beating(heartbeat, published).await {
+                    log::warn!("Error publishing command result for {}: {}", spec.name, e);
+                }
+            }
+            _ = held => beating(heartbeat, gateway.flush(&spec.name, filters)).await,
+            _ = beats.tick() => heartbeat.beat(),
+            _ = tick(summaries) => {
+                if let Some(window) = window.as_mut() {
+                    let summary = gateway.summarize(&spec.name, &view, window, Utc::now(), filters);
+                    beating(heartbeat, summary).await;
                 }
-                Either::Right(_) => {
-                    log::info!("Timeout waiting for event, removing device");
-                    let _ = adapter.remove_device(address).await;
-                    break;
+            }
+            _ = gateway.stopped() => {
+                log::info!("Disconnecting from {}", spec.name);
+                // End the notification streams before the connection, so BlueZ is left clean
+                drop(s);
+                if let Err(e) = board.disconnect().await {
+                    log::warn!("Error disconnecting from {}: {}", spec.name, e);
                 }
+                return Ok(());
+            }
+            _ = timeout => {
+                log::info!(
+                    "Timeout waiting for event from {}, removing device",
+                    spec.name
+                );
+                metrics::RECONNECTS
+                    .with_label_values(&[&spec.name, "timeout"])
+                    .inc();
+                link.remove_device(spec.address).await;
+                return Ok(());
             }
         }
-        log::info!("BLE sensor disconnected");
+    }
+}
+
+/// Publishes the values of a recording as if they were received from the devices.
+async fn run_replay(path: &Path, speed: f64, gateway: &Gateway) -> anyhow::Result<()> {
+    let records =
+        recording::read(path).with_context(|| format!("reading recording {}", path.display()))?;
+    log::info!("Replaying {} values from {}", records.len(), path.display());
+    let mut devices: HashMap<String, Replayed> = HashMap::new();
+    let s = recording::replay(records, speed)?;
+    pin_mut!(s);
+    let mut ended = false;
+    let heartbeat = gateway.heartbeats.register("replay");
+    let mut beats = tokio::time::interval(HEARTBEAT_INTERVAL);
+    // Windows of all devices are summarized together, as they start with the replay
+    let mut summaries = gateway
+        .aggregation
+        .window()
+        .map(|w| tokio::time::interval_at(tokio::time::Instant::now() + w.step(), w.step()));
+    loop {
+        // Readings held back by the filters are published when due, even after the last one
+        let due = devices.values().filter_map(|d| d.filters.due()).min();
+        if ended && due.is_none() {
+            break;
+        }
+        let record = tokio::select! {
+            record = s.next(), if !ended => record,
+            _ = until(due) => {
+                for (device, d) in devices.iter_mut() {
+                    beating(&heartbeat, gateway.flush(device, &mut d.filters)).await;
+                }
+                continue;
+            }
+            _ = tick(&mut summaries) => {
+                for (device, d) in devices.iter_mut() {
+                    beating(&heartbeat, d.summarize(gateway, device)).await;
+                }
+                continue;
+            }
+            _ = beats.tick() => {
+                heartbeat.beat();
+                continue;
+            }
+            _ = gateway.stopped() => break,
+        };
+        let record = match record {
+            Some(record) => record,
+            None => {
+                // Readings since the last summary are summarized rather than lost
+                for (device, d) in devices.iter_mut() {
+                    if d.pending {
+                        beating(&heartbeat, d.summarize(gateway, device)).await;
+                    }
+                }
+                ended = true;
+                continue;
+            }
+        };
+        // Values are decoded as they were when recorded, which depends on the firmware
+        let decoders = board::decoders_for_firmware(
+            &gateway.decoders,
+            record.firmware_revision.as_deref(),
+            gateway.decoding.temperature_exponent,
+        );
+        let decoder = match decoders.get(&record.characteristic) {
+            Some(decoder) => decoder,
+            None => {
+                log::debug!("No decoder for {}, skipping", record.characteristic);
+                continue;
+            }
+        };
+        let reading = match decoder.decode(&record.data) {
+            Ok(reading) => reading,
+            Err(e) => {
+                log::warn!("Skipping value from {}: {}", record.device, e);
+                continue;
+            }
+        };
+        let d = devices
+            .entry(record.device.clone())
+            .or_insert_with(|| Replayed {
+                view: json!({}),
+                window: gateway.aggregation.window(),
+                pending: false,
+                time: record.timestamp,
+                filters: Filters::new(gateway.filters.clone()),
+            });
+        d.time = record.timestamp;
+        match d.window.as_mut() {
+            Some(window) => {
+                gateway.update(
+                    &record.device,
+                    &record.address,
+                    &mut d.view,
+                    &reading,
+                    record.timestamp,
+                );
+                window.add(tokio::time::Instant::now(), &reading);
+                d.pending = true;
+            }
+            None => {
+                let reading = gateway.reading(
+                    &record.device,
+                    &record.address,
+                    &mut d.view,
+                    &reading,
+                    record.timestamp,
+                    &mut d.filters,
+                );
+                beating(&heartbeat, reading).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// State of a replayed device, as a device task would keep it.
+struct Replayed {
+    view: serde_json::Value,
+    window: Option<Window>,
+    /// Whether readings were added to the window since it was last summarized.
+    pending: bool,
+    /// When the latest reading was recorded, which summaries are published at.
+    time: DateTime<Utc>,
+    filters: Filters,
+}
+
+impl Replayed {
+    async fn summarize(&mut self, gateway: &Gateway, device: &str) {
+        if let Some(window) = self.window.as_mut() {
+            self.pending = false;
+            gateway
+                .summarize(device, &self.view, window, self.time, &mut self.filters)
+                .await;
+        }
+    }
+}
+
+/// The reporting interval of a board, in the shape of a sensor reading.
+fn interval_reading(interval: u8) -> serde_json::Value {
+    json!({ "interval": { "value": interval, "unit": "s" } })
+}
+
+/// Runs an operation that takes long but keeps making progress, such as a firmware update,
+/// connecting or publishing over a slow uplink, beating while it does.
+async fn beating<F: std::future::Future>(heartbeat: &Heartbeat, f: F) -> F::Output {
+    pin_mut!(f);
+    let mut beats = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        tokio::select! {
+            output = &mut f => return output,
+            _ = beats.tick() => heartbeat.beat(),
+        }
+    }
+}
+
+/// Waits for the next tick of an interval that may not be set, in which case it never ticks.
+async fn tick(interval: &mut Option<tokio::time::Interval>) -> tokio::time::Instant {
+    match interval {
+        Some(interval) => interval.tick().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits until a deadline that may not be set, in which case it never passes.
+async fn until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FieldFilter;
+    use crate::simulator::SimulatorConfig;
+
+    /// A gateway keeping published messages in memory, and the sender to shut it down with.
+    pub(crate) fn gateway() -> (Gateway, watch::Sender<bool>) {
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let gateway = Gateway {
+            id: "test".to_string(),
+            boot_id: uuid::Uuid::new_v4(),
+            sink: Sink::Memory(Default::default()),
+            report_interval: 1,
+            firmware: None,
+            reconnect: ReconnectPolicy {
+                jitter: 0.0,
+                ..Default::default()
+            },
+            aggregation: AggregationConfig::default(),
+            filters: FilterConfig::default(),
+            decoders: Arc::new(Registry::default()),
+            decoding: DecodingConfig::default(),
+            recorder: None,
+            commands: Dispatcher::default(),
+            states: Arc::new(DeviceStates::default()),
+            sequences: Mutex::new(HashMap::new()),
+            shutdown,
+            systemd: Notifier::new(&Default::default()),
+            heartbeats: Arc::new(Heartbeats::default()),
+        };
+        (gateway, shutdown_tx)
+    }
+
+    fn spec(address: &str, name: &str) -> DeviceSpec {
+        DeviceSpec {
+            address: address.parse().unwrap(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn liveness_timeout_has_slack_for_short_intervals() {
+        let board = Microbit::with_client(MockPeer::new().client());
+
+        assert_eq!(liveness_timeout(&board, 0), Duration::from_secs(10));
+        assert_eq!(liveness_timeout(&board, 1), Duration::from_secs(11));
+        assert_eq!(liveness_timeout(&board, 10), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_firmware_updates_with_backoff() {
+        let mut updates = UpdateAttempts::default();
+        let hours = |n: u64| tokio::time::sleep(Duration::from_secs(n * 60 * 60));
+        assert!(updates.due("microbit", "1.0", "1.1"));
+        updates.attempted("1.1");
+
+        // The update didn't take
+        assert!(!updates.due("microbit", "1.0", "1.1"));
+        hours(1).await;
+        assert!(updates.due("microbit", "1.0", "1.1"));
+        updates.attempted("1.1");
+        hours(1).await;
+        assert!(!updates.due("microbit", "1.0", "1.1"));
+        hours(1).await;
+        assert!(updates.due("microbit", "1.0", "1.1"));
+
+        // A new version is installed right away
+        assert!(updates.due("microbit", "1.0", "1.2"));
+        updates.attempted("1.2");
+        assert!(!updates.due("microbit", "1.2", "1.2"));
+        assert_eq!(updates.count, 0);
+    }
+
+    #[tokio::test]
+    async fn stamps_readings_when_received() {
+        let (gateway, _shutdown) = gateway();
+        let mut view = json!({});
+        let mut filters = Filters::new(FilterConfig::default());
+        let received = "2022-05-13T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        for value in [21.0, 21.5] {
+            let reading = json!({ "temperature": { "value": value, "unit": "°C" } });
+            gateway
+                .reading(
+                    "microbit",
+                    "E2:9A:A8:1C:CB:0A",
+                    &mut view,
+                    &reading,
+                    received,
+                    &mut filters,
+                )
+                .await;
+        }
+
+        let published = gateway.sink.published();
+        assert_eq!(published[0].1["timestamp"], json!(received));
+        assert_eq!(published[0].1["seq"], 1);
+        assert_eq!(published[1].1["seq"], 2);
+        assert_eq!(published[0].1["boot"], json!(gateway.boot_id));
+        assert_ne!(published[0].1["id"], published[1].1["id"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_with_recorded_timestamps() {
+        let (gateway, _shutdown) = gateway();
+        let recorded = "2022-05-13T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let record = recording::Record {
+            timestamp: recorded,
+            device: "microbit".to_string(),
+            address: "E2:9A:A8:1C:CB:0A".to_string(),
+            characteristic: crate::decoders::TEMPERATURE_CELSIUS_UUID,
+            data: vec![215, 0],
+            firmware_revision: Some("2.0".to_string()),
+        };
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", uuid::Uuid::new_v4()));
+        std::fs::write(&path, serde_json::to_string(&record).unwrap()).unwrap();
+
+        run_replay(&path, 1.0, &gateway).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let published = gateway.sink.published();
+        assert_eq!(published[0].1["timestamp"], json!(recorded));
+        assert_eq!(published[0].1["temperature"]["value"], 21.5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_with_the_recorded_firmware_scale() {
+        let (gateway, _shutdown) = gateway();
+        let record = recording::Record {
+            timestamp: "2022-05-13T10:00:00Z".parse().unwrap(),
+            device: "microbit".to_string(),
+            address: "E2:9A:A8:1C:CB:0A".to_string(),
+            characteristic: crate::decoders::TEMPERATURE_CELSIUS_UUID,
+            data: vec![21, 0],
+            firmware_revision: Some("1.0".to_string()),
+        };
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", uuid::Uuid::new_v4()));
+        std::fs::write(&path, serde_json::to_string(&record).unwrap()).unwrap();
+
+        run_replay(&path, 1.0, &gateway).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let published = gateway.sink.published();
+        assert_eq!(published[0].1["temperature"]["value"], 21.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_readings_through_aggregation_windows() {
+        let (mut gateway, _shutdown) = gateway();
+        gateway.aggregation = AggregationConfig {
+            window: Some(Duration::from_secs(60)),
+            step: None,
+        };
+        let records: Vec<_> = [("10:00:00", 20), ("10:00:20", 22), ("10:00:40", 24)]
+            .iter()
+            .map(|(time, temperature)| {
+                let record = recording::Record {
+                    timestamp: format!("2022-05-13T{}Z", time).parse().unwrap(),
+                    device: "microbit".to_string(),
+                    address: "E2:9A:A8:1C:CB:0A".to_string(),
+                    characteristic: crate::decoders::TEMPERATURE_CELSIUS_UUID,
+                    data: vec![*temperature, 0],
+                    firmware_revision: Some("1.0".to_string()),
+                };
+                serde_json::to_string(&record).unwrap()
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", uuid::Uuid::new_v4()));
+        std::fs::write(&path, records.join("\n")).unwrap();
+
+        run_replay(&path, 1.0, &gateway).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let published = gateway.sink.published();
+        assert_eq!(published.len(), 1);
+        let temperature = &published[0].1["temperature"];
+        assert_eq!(temperature["count"], 3);
+        assert_eq!(temperature["mean"], 22.0);
+        assert_eq!(temperature["min"], 20.0);
+        assert_eq!(temperature["max"], 24.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn publishes_readings_held_back_by_min_interval() {
+        let (mut gateway, _shutdown) = gateway();
+        gateway.filters.insert(
+            "temperature".to_string(),
+            FieldFilter {
+                min_interval: Some(Duration::from_secs(30)),
+                ..Default::default()
+            },
+        );
+        let start = "2022-05-13T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let records: Vec<String> = [(0, 200i16), (5, 210), (10, 215)]
+            .iter()
+            .map(|(offset, value)| {
+                serde_json::to_string(&recording::Record {
+                    timestamp: start + chrono::Duration::seconds(*offset),
+                    device: "microbit".to_string(),
+                    address: "E2:9A:A8:1C:CB:0A".to_string(),
+                    characteristic: crate::decoders::TEMPERATURE_CELSIUS_UUID,
+                    data: value.to_le_bytes().to_vec(),
+                    firmware_revision: Some("2.0".to_string()),
+                })
+                .unwrap()
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", uuid::Uuid::new_v4()));
+        std::fs::write(&path, records.join("\n")).unwrap();
+
+        let begin = tokio::time::Instant::now();
+        run_replay(&path, 1.0, &gateway).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let published = gateway.sink.published();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0].1["temperature"]["value"], 20.0);
+        // The latest reading, with the time it was received, once the interval is over
+        assert_eq!(published[1].1["temperature"]["value"], 21.5);
+        assert_eq!(
+            published[1].1["timestamp"],
+            json!(start + chrono::Duration::seconds(10))
+        );
+        assert_eq!(begin.elapsed(), Duration::from_secs(30));
+    }
+
+    fn published_by(gateway: &Gateway, device: &str) -> usize {
+        gateway
+            .sink
+            .published()
+            .iter()
+            .filter(|(d, _)| d == device)
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_device_does_not_stall_others() {
+        let (gateway, _shutdown) = gateway();
+        let gateway = Arc::new(gateway);
+        let flaky = simulator::spawn(SimulatorConfig::default(), 1);
+        flaky.fail_connects(usize::MAX);
+        let steady = simulator::spawn(SimulatorConfig::default(), 1);
+        tokio::spawn(run_device(
+            spec("00:00:00:00:00:01", "flaky"),
+            gateway.clone(),
+            Link::Simulated(flaky),
+        ));
+        tokio::spawn(run_device(
+            spec("00:00:00:00:00:02", "steady"),
+            gateway.clone(),
+            Link::Simulated(steady),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+
+        assert!(published_by(&gateway, "steady") >= 25);
+        assert_eq!(gateway.heartbeats.stale(HEARTBEAT_INTERVAL * 2), None);
+        assert_eq!(published_by(&gateway, "flaky"), 0);
+        assert!(gateway.states.get("steady").unwrap().connected);
+        assert!(!gateway.states.get("flaky").unwrap().connected);
+        assert!(
+            metrics::CONNECTION_ATTEMPTS
+                .with_label_values(&["flaky"])
+                .get()
+                >= 4
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_uplink_does_not_time_out_devices() {
+        let (gateway, _shutdown) = gateway();
+        if let Sink::Memory(memory) = &gateway.sink {
+            memory.slow_down(Duration::from_secs(5));
+        }
+        let gateway = Arc::new(gateway);
+        let peer = simulator::spawn(SimulatorConfig::default(), 1);
+        tokio::spawn(run_device(
+            spec("00:00:00:00:00:06", "slow"),
+            gateway.clone(),
+            Link::Simulated(peer),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        assert!(published_by(&gateway, "slow") >= 10);
+        assert_eq!(
+            metrics::RECONNECTS
+                .with_label_values(&["slow", "timeout"])
+                .get(),
+            0
+        );
+        assert_eq!(
+            metrics::CONNECTION_ATTEMPTS
+                .with_label_values(&["slow"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_uplink_keeps_heartbeat() {
+        let (gateway, _shutdown) = gateway();
+        if let Sink::Memory(memory) = &gateway.sink {
+            // Longer than the watchdog of the shipped service
+            memory.slow_down(Duration::from_secs(60));
+        }
+        let gateway = Arc::new(gateway);
+        let peer = simulator::spawn(SimulatorConfig::default(), 1);
+        tokio::spawn(run_device(
+            spec("00:00:00:00:00:07", "waiting"),
+            gateway.clone(),
+            Link::Simulated(peer),
+        ));
+
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            assert_eq!(gateway.heartbeats.stale(HEARTBEAT_INTERVAL * 2), None);
+        }
+        assert!(gateway.states.get("waiting").unwrap().connected);
+        assert!(published_by(&gateway, "waiting") >= 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn publishes_initial_interval() {
+        let (gateway, _shutdown) = gateway();
+        let gateway = Arc::new(gateway);
+        let peer = simulator::spawn(SimulatorConfig::default(), 1);
+        tokio::spawn(run_device(
+            spec("00:00:00:00:00:04", "fresh"),
+            gateway.clone(),
+            Link::Simulated(peer),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+
+        let published = gateway.sink.published();
+        // Only readings from the board count as received, not the interval read on connect
+        assert_eq!(
+            metrics::NOTIFICATIONS.with_label_values(&["fresh"]).get() as usize,
+            published_by(&gateway, "fresh") - 1
+        );
+        let (_, first) = published.iter().find(|(d, _)| d == "fresh").unwrap();
+        assert_eq!(first["interval"], json!({ "value": 1, "unit": "s" }));
+        assert_eq!(
+            gateway.states.get("fresh").unwrap().state["interval"]["value"],
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn summarizes_across_reconnects() {
+        let (mut gateway, _shutdown) = gateway();
+        gateway.aggregation.window = Some(Duration::from_secs(10));
+        let gateway = Arc::new(gateway);
+        // Never connected for a whole window
+        let peer = simulator::spawn(
+            SimulatorConfig {
+                disconnect_every: Some(Duration::from_secs(7)),
+                ..Default::default()
+            },
+            1,
+        );
+        tokio::spawn(run_device(
+            spec("00:00:00:00:00:05", "summarized"),
+            gateway.clone(),
+            Link::Simulated(peer),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(61)).await;
+
+        let published = gateway.sink.published();
+        assert!(published_by(&gateway, "summarized") >= 5);
+        assert!(published
+            .iter()
+            .all(|(_, message)| message["temperature"]["mean"].is_number()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_dropped_devices() {
+        let (gateway, _shutdown) = gateway();
+        let gateway = Arc::new(gateway);
+        let peer = simulator::spawn(
+            SimulatorConfig {
+                disconnect_every: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+            1,
+        );
+        tokio::spawn(run_device(
+            spec("00:00:00:00:00:03", "dropping"),
+            gateway.clone(),
+            Link::Simulated(peer),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        let before = published_by(&gateway, "dropping");
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        assert!(
+            metrics::CONNECTION_ATTEMPTS
+                .with_label_values(&["dropping"])
+                .get()
+                >= 3
+        );
+        assert!(published_by(&gateway, "dropping") > before);
     }
 }
@@ -0,0 +1,159 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+lazy_static! {
+    pub static ref CONNECTION_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        "gateway_connection_attempts_total",
+        "Number of attempts to connect to a device",
+        &["device"]
+    )
+    .unwrap();
+    pub static ref RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "gateway_reconnects_total",
+        "Number of times a device connection was dropped, by reason",
+        &["device", "reason"]
+    )
+    .unwrap();
+    pub static ref NOTIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "gateway_notifications_total",
+        "Number of sensor notifications received from a device",
+        &["device"]
+    )
+    .unwrap();
+    pub static ref FILTERED: IntCounterVec = register_int_counter_vec!(
+        "gateway_filtered_total",
+        "Number of readings of a device not published because of the publishing filters",
+        &["device"]
+    )
+    .unwrap();
+    pub static ref PUBLISHED: IntCounterVec = register_int_counter_vec!(
+        "gateway_publish_total",
+        "Number of messages published to the uplink, by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "gateway_queue_depth",
+        "Number of readings waiting in the store-and-forward queue"
+    )
+    .unwrap();
+    pub static ref CONSECUTIVE_FAILURES: IntGaugeVec = register_int_gauge_vec!(
+        "gateway_consecutive_failures",
+        "Number of failed attempts to connect to a device since it last streamed",
+        &["device"]
+    )
+    .unwrap();
+    pub static ref GIVEN_UP: IntCounterVec = register_int_counter_vec!(
+        "gateway_devices_given_up_total",
+        "Number of times the gateway stopped retrying a device",
+        &["device"]
+    )
+    .unwrap();
+    pub static ref ADAPTER_POWER_CYCLES: IntCounter = register_int_counter!(
+        "gateway_adapter_power_cycles_total",
+        "Number of times the Bluetooth adapter was power cycled to recover devices"
+    )
+    .unwrap();
+    pub static ref CONNECTED: IntGaugeVec = register_int_gauge_vec!(
+        "gateway_device_connected",
+        "Whether the gateway is currently streaming from a device",
+        &["device"]
+    )
+    .unwrap();
+    static ref LAST_READING_AGE: GaugeVec = register_gauge_vec!(
+        "gateway_last_reading_age_seconds",
+        "Time since the last reading was received from a device",
+        &["device"]
+    )
+    .unwrap();
+    static ref LAST_READING: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Records a notification received from a device.
+pub fn reading_received(device: &str) {
+    NOTIFICATIONS.with_label_values(&[device]).inc();
+    LAST_READING
+        .lock()
+        .unwrap()
+        .insert(device.to_string(), Instant::now());
+}
+
+fn encode() -> Vec<u8> {
+    for (device, at) in LAST_READING.lock().unwrap().iter() {
+        LAST_READING_AGE
+            .with_label_values(&[device])
+            .set(at.elapsed().as_secs_f64());
+    }
+
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::warn!("Error encoding metrics: {}", e);
+    }
+    buffer
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(
+                hyper::header::CONTENT_TYPE,
+                TextEncoder::new().format_type(),
+            )
+            .body(Body::from(encode())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+/// Serves metrics on `http://<addr>/metrics`.
+pub async fn serve(addr: SocketAddr) -> hyper::Result<()> {
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    Server::try_bind(&addr)?.serve(make_svc).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(path: &str) -> (StatusCode, String) {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        let response = handle(req).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_device_metrics() {
+        reading_received("metrics-test");
+        reading_received("metrics-test");
+        CONNECTED.with_label_values(&["metrics-test"]).set(1);
+
+        let (status, body) = get("/metrics").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("gateway_notifications_total{device=\"metrics-test\"} 2"));
+        assert!(body.contains("gateway_device_connected{device=\"metrics-test\"} 1"));
+        assert!(body.contains("gateway_last_reading_age_seconds{device=\"metrics-test\"}"));
+    }
+
+    #[tokio::test]
+    async fn serves_nothing_else() {
+        assert_eq!(get("/").await.0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::gatt::{GattClient, Notifications};
+use async_trait::async_trait;
+use bluer::{Error, ErrorKind};
+use futures::channel::mpsc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct State {
+    connected: bool,
+    connect_failures: usize,
+    services: HashMap<uuid::Uuid, Vec<uuid::Uuid>>,
+    values: HashMap<uuid::Uuid, Vec<u8>>,
+    descriptors: HashMap<uuid::Uuid, Vec<(uuid::Uuid, Vec<u8>)>>,
+    subscribers: HashMap<uuid::Uuid, Vec<mpsc::UnboundedSender<Vec<u8>>>>,
+    writes: Vec<(uuid::Uuid, Vec<u8>)>,
+}
+
+/// In-memory GATT peer, used by tests and the simulator. Clones share the same state, so a
+/// handle to the peer can be kept while a `MockClient` is connected to it.
+#[derive(Clone, Default)]
+pub struct MockPeer {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockPeer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn client(&self) -> MockClient {
+        MockClient { peer: self.clone() }
+    }
+
+    pub fn add_characteristic(&self, service: uuid::Uuid, c: uuid::Uuid, value: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.services.entry(service).or_default().push(c);
+        state.values.insert(c, value.to_vec());
+    }
+
+    pub fn add_descriptor(&self, c: uuid::Uuid, descriptor: uuid::Uuid, value: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .descriptors
+            .entry(c)
+            .or_default()
+            .push((descriptor, value.to_vec()));
+    }
+
+    /// Updates the characteristic value and notifies subscribers.
+    pub fn notify(&self, c: uuid::Uuid, value: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.values.insert(c, value.to_vec());
+        if let Some(subscribers) = state.subscribers.get_mut(&c) {
+            subscribers.retain(|s| s.unbounded_send(value.to_vec()).is_ok());
+        }
+    }
+
+    /// Drops the connection, ending all notification streams.
+    pub fn disconnect(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.connected = false;
+        state.subscribers.clear();
+    }
+
+    /// Makes the next `n` connection attempts fail.
+    #[cfg(test)]
+    pub fn fail_connects(&self, n: usize) {
+        self.state.lock().unwrap().connect_failures = n;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    pub fn value(&self, c: uuid::Uuid) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().values.get(&c).cloned()
+    }
+
+    /// All values written by clients, in order.
+    #[cfg(test)]
+    pub fn writes(&self) -> Vec<(uuid::Uuid, Vec<u8>)> {
+        self.state.lock().unwrap().writes.clone()
+    }
+}
+
+pub struct MockClient {
+    peer: MockPeer,
+}
+
+impl MockClient {
+    fn connected(&self) -> bluer::Result<std::sync::MutexGuard<'_, State>> {
+        let state = self.peer.state.lock().unwrap();
+        if state.connected {
+            Ok(state)
+        } else {
+            Err(Error {
+                kind: ErrorKind::NotReady,
+                message: "mock peer not connected".into(),
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl GattClient for MockClient {
+    type Characteristic = uuid::Uuid;
+
+    async fn connect(&mut self) -> bluer::Result<()> {
+        let mut state = self.peer.state.lock().unwrap();
+        if state.connect_failures > 0 {
+            state.connect_failures -= 1;
+            return Err(Error {
+                kind: ErrorKind::Failed,
+                message: "mock connection failure".into(),
+            });
+        }
+        state.connected = true;
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> bluer::Result<()> {
+        self.peer.disconnect();
+        Ok(())
+    }
+
+    async fn is_connected(&mut self) -> bluer::Result<bool> {
+        Ok(self.peer.is_connected())
+    }
+
+    async fn discover_services(&mut self) -> bluer::Result<Vec<uuid::Uuid>> {
+        Ok(self.connected()?.services.keys().copied().collect())
+    }
+
+    async fn find_characteristic(
+        &mut self,
+        service: uuid::Uuid,
+        characteristic: uuid::Uuid,
+    ) -> bluer::Result<Option<uuid::Uuid>> {
+        let state = self.connected()?;
+        Ok(state
+            .services
+            .get(&service)
+            .and_then(|chars| chars.iter().find(|c| **c == characteristic))
+            .copied())
+    }
+
+    async fn read(&mut self, c: &uuid::Uuid) -> bluer::Result<Vec<u8>> {
+        Ok(self.connected()?.values.get(c).cloned().unwrap_or_default())
+    }
+
+    async fn write(&mut self, c: &uuid::Uuid, value: &[u8]) -> bluer::Result<()> {
+        let mut state = self.connected()?;
+        state.values.insert(*c, value.to_vec());
+        state.writes.push((*c, value.to_vec()));
+        Ok(())
+    }
+
+    async fn notify(&mut self, c: &uuid::Uuid) -> bluer::Result<Notifications> {
+        let (tx, rx) = mpsc::unbounded();
+        self.connected()?
+            .subscribers
+            .entry(*c)
+            .or_default()
+            .push(tx);
+        Ok(Box::pin(rx))
+    }
+
+    async fn read_descriptors(
+        &mut self,
+        c: &uuid::Uuid,
+        descriptor: uuid::Uuid,
+    ) -> bluer::Result<Vec<Vec<u8>>> {
+        let state = self.connected()?;
+        Ok(state
+            .descriptors
+            .get(c)
+            .map(|d| {
+                d.iter()
+                    .filter(|(uuid, _)| *uuid == descriptor)
+                    .map(|(_, value)| value.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
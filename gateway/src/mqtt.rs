@@ -0,0 +1,481 @@
+use crate::commands::Command;
+use crate::metrics;
+use anyhow::Context;
+use rumqttc::{
+    AsyncClient, ClientError, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS, Transport,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic to publish to, `{device}` is replaced with the device name.
+    pub topic: String,
+    pub qos: QoS,
+    pub tls: bool,
+    /// How long a publish waits for room in the client's request queue.
+    pub timeout: Duration,
+    /// Topic filter to receive commands on. Commands are expected on
+    /// `<prefix>/<device>/<command>` topics, as used by Drogue Cloud.
+    pub command_topic: Option<String>,
+}
+
+/// Publishes telemetry to an MQTT broker, reconnecting in the background.
+pub struct MqttPublisher {
+    client: AsyncClient,
+    topic: String,
+    qos: QoS,
+    timeout: Duration,
+    unacked: Arc<Unacked>,
+    eventloop: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Published messages the broker has not acknowledged yet.
+#[derive(Default)]
+struct Unacked {
+    count: AtomicUsize,
+    changed: Notify,
+}
+
+impl Unacked {
+    fn add(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn done(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+
+    /// The broker took a message over.
+    fn acknowledged(&self) {
+        metrics::PUBLISHED.with_label_values(&["success"]).inc();
+        self.done();
+    }
+
+    async fn none(&self) {
+        loop {
+            // Created before checking, so a change in between is not missed
+            let changed = self.changed.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+impl MqttPublisher {
+    pub fn new(config: MqttConfig, commands: mpsc::UnboundedSender<Command>) -> Self {
+        let mut options = MqttOptions::new(config.client_id, config.host, config.port);
+        // Keep the session across reconnects so that queued QoS 1/2 messages are delivered
+        options.set_clean_session(false);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = config.username {
+            options.set_credentials(username, config.password.unwrap_or_default());
+        }
+        if config.tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        let unacked = Arc::new(Unacked::default());
+        let eventloop = tokio::spawn(run_eventloop(
+            eventloop,
+            client.clone(),
+            config.command_topic,
+            commands,
+            config.qos,
+            unacked.clone(),
+        ));
+        Self {
+            client,
+            topic: config.topic,
+            qos: config.qos,
+            timeout: config.timeout,
+            unacked,
+            eventloop: Mutex::new(Some(eventloop)),
+        }
+    }
+
+    /// Hands the payload to the event loop for publishing. The client's request queue fills up
+    /// while the broker is unreachable, so this waits up to the timeout for room. A message
+    /// that doesn't get any is refused, so that a telemetry queue can keep it. Messages only
+    /// count as published once the broker acknowledged them.
+    pub async fn publish(&self, device: &str, payload: &serde_json::Value) -> anyhow::Result<()> {
+        let topic = topic(&self.topic, device);
+        // Counted before the event loop can see the message, so it is never acknowledged first
+        self.unacked.add();
+        let publish = self
+            .client
+            .publish(topic, self.qos, false, payload.to_string());
+        let result = match tokio::time::timeout(self.timeout, publish).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(e) => Err(e).context("MQTT client busy, the broker may be unreachable"),
+        };
+        if result.is_err() {
+            metrics::PUBLISHED.with_label_values(&["failure"]).inc();
+            self.unacked.done();
+        }
+        result
+    }
+
+    /// Waits until the broker acknowledged all published messages. With QoS 0 messages count
+    /// as acknowledged once they are sent.
+    pub async fn acknowledged(&self) {
+        self.unacked.none().await
+    }
+
+    /// Waits until the broker acknowledged all published messages, then disconnects.
+    pub async fn flush(&self) -> Result<(), ClientError> {
+        self.unacked.none().await;
+        self.client.disconnect().await?;
+        let eventloop = self.eventloop.lock().unwrap().take();
+        if let Some(eventloop) = eventloop {
+            let _ = eventloop.await;
+        }
+        Ok(())
+    }
+}
+
+/// Topic to publish the telemetry of `device` to.
+fn topic(template: &str, device: &str) -> String {
+    template.replace("{device}", device)
+}
+
+pub fn parse_qos(s: &str) -> Result<QoS, String> {
+    match s {
+        "0" => Ok(QoS::AtMostOnce),
+        "1" => Ok(QoS::AtLeastOnce),
+        "2" => Ok(QoS::ExactlyOnce),
+        _ => Err(format!("invalid QoS '{}', must be 0, 1 or 2", s)),
+    }
+}
+
+// The event loop must be polled for the client to make progress. Polling again after
+// an error makes it reconnect.
+async fn run_eventloop(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    command_topic: Option<String>,
+    commands: mpsc::UnboundedSender<Command>,
+    qos: QoS,
+    unacked: Arc<Unacked>,
+) {
+    loop {
+        match eventloop.poll().await {
+            // Telemetry is the only thing published, so these complete published messages
+            Ok(Event::Outgoing(Outgoing::Publish(_))) if qos == QoS::AtMostOnce => {
+                unacked.acknowledged()
+            }
+            Ok(Event::Incoming(Packet::PubAck(_))) if qos == QoS::AtLeastOnce => {
+                unacked.acknowledged()
+            }
+            Ok(Event::Incoming(Packet::PubComp(_))) if qos == QoS::ExactlyOnce => {
+                unacked.acknowledged()
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                log::info!("Disconnected from MQTT broker");
+                return;
+            }
+            Ok(Event::Incoming(Packet::ConnAck(ack))) => {
+                log::info!("Connected to MQTT broker: {:?}", ack.code);
+                if let Some(topic) = &command_topic {
+                    if let Err(e) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                        log::warn!("Error subscribing to commands on {}: {}", topic, e);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if let Some(command) = parse_command(&publish.topic, &publish.payload) {
+                    let _ = commands.send(command);
+                } else {
+                    log::debug!("Ignoring message on {}", publish.topic);
+                }
+            }
+            Ok(event) => {
+                log::trace!("MQTT event: {:?}", event);
+            }
+            Err(e) => {
+                log::warn!("MQTT connection error, reconnecting: {}", e);
+                sleep(Duration::from_secs(2)).await;
+            }
+        }
+    }
+}
+
+fn parse_command(topic: &str, payload: &[u8]) -> Option<Command> {
+    let mut segments = topic.rsplit('/');
+    let name = segments.next().filter(|s| !s.is_empty())?;
+    let device = segments.next().filter(|s| !s.is_empty())?;
+    Some(Command {
+        device: device.to_string(),
+        name: name.to_string(),
+        payload: payload.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use rumqttc::mqttbytes::v4::{self, ConnAck, ConnectReturnCode, PubAck, Publish};
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::Semaphore;
+    use tokio::time::timeout;
+
+    /// What a broker received from the gateway.
+    #[derive(Default)]
+    struct Received {
+        /// Clean session flags of the connections.
+        connects: Vec<bool>,
+        publishes: Vec<Publish>,
+        disconnected: bool,
+    }
+
+    /// Broker that acknowledges QoS 1 publishes only as it gets permits for them, and drops
+    /// the connection when told to.
+    struct Broker {
+        port: u16,
+        received: Arc<Mutex<Received>>,
+        acks: Arc<Semaphore>,
+        drop: Arc<Notify>,
+    }
+
+    impl Broker {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let broker = Self {
+                port: listener.local_addr().unwrap().port(),
+                received: Default::default(),
+                acks: Arc::new(Semaphore::new(0)),
+                drop: Default::default(),
+            };
+            let (received, acks, drop) = (
+                broker.received.clone(),
+                broker.acks.clone(),
+                broker.drop.clone(),
+            );
+            tokio::spawn(async move {
+                let mut sessions = 0;
+                while let Ok((socket, _)) = listener.accept().await {
+                    serve(socket, sessions > 0, &received, &acks, &drop).await;
+                    sessions += 1;
+                }
+            });
+            broker
+        }
+
+        fn config(&self) -> MqttConfig {
+            MqttConfig {
+                host: "127.0.0.1".to_string(),
+                port: self.port,
+                client_id: "gateway".to_string(),
+                username: None,
+                password: None,
+                topic: "sensors/{device}".to_string(),
+                qos: QoS::AtLeastOnce,
+                tls: false,
+                timeout: Duration::from_secs(5),
+                command_topic: None,
+            }
+        }
+
+        fn publisher(&self) -> MqttPublisher {
+            let (commands, _) = mpsc::unbounded_channel();
+            MqttPublisher::new(self.config(), commands)
+        }
+
+        /// Waits until the broker received `n` publishes.
+        async fn publishes(&self, n: usize) {
+            self.until(|r| r.publishes.len() >= n).await
+        }
+
+        async fn until(&self, f: impl Fn(&Received) -> bool) {
+            while !f(&self.received.lock().unwrap()) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
+    async fn serve(
+        mut socket: TcpStream,
+        session_present: bool,
+        received: &Mutex<Received>,
+        acks: &Semaphore,
+        drop: &Notify,
+    ) {
+        let mut buf = BytesMut::new();
+        let mut unacked = Vec::new();
+        let mut out = BytesMut::new();
+        loop {
+            tokio::select! {
+                n = socket.read_buf(&mut buf) => {
+                    if !matches!(n, Ok(n) if n > 0) {
+                        return;
+                    }
+                }
+                permit = acks.acquire(), if !unacked.is_empty() => {
+                    permit.unwrap().forget();
+                    PubAck::new(unacked.remove(0)).write(&mut out).unwrap();
+                }
+                _ = drop.notified() => return,
+            }
+            loop {
+                match v4::read(&mut buf, 1024 * 1024) {
+                    Ok(Packet::Connect(connect)) => {
+                        received
+                            .lock()
+                            .unwrap()
+                            .connects
+                            .push(connect.clean_session);
+                        ConnAck::new(ConnectReturnCode::Success, session_present)
+                            .write(&mut out)
+                            .unwrap();
+                    }
+                    Ok(Packet::Publish(publish)) => {
+                        unacked.push(publish.pkid);
+                        received.lock().unwrap().publishes.push(publish);
+                    }
+                    Ok(Packet::Disconnect) => {
+                        received.lock().unwrap().disconnected = true;
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+            if socket.write_all(&out.split()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn acknowledged_once_broker_acks() {
+        let broker = Broker::start().await;
+        let publisher = broker.publisher();
+
+        publisher
+            .publish("kitchen", &json!({ "seq": 1 }))
+            .await
+            .unwrap();
+        broker.publishes(1).await;
+        assert!(
+            timeout(Duration::from_millis(200), publisher.acknowledged())
+                .await
+                .is_err()
+        );
+
+        broker.acks.add_permits(1);
+        timeout(Duration::from_secs(5), publisher.acknowledged())
+            .await
+            .unwrap();
+        let received = broker.received.lock().unwrap();
+        assert_eq!(received.publishes[0].topic, "sensors/kitchen");
+        assert_eq!(
+            received.publishes[0].payload,
+            json!({ "seq": 1 }).to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_messages_while_broker_is_unreachable() {
+        let broker = Broker::start().await;
+        let publisher = MqttPublisher::new(
+            MqttConfig {
+                // Nothing listens there
+                port: 1,
+                timeout: Duration::from_millis(100),
+                ..broker.config()
+            },
+            mpsc::unbounded_channel().0,
+        );
+
+        let mut refused = 0;
+        for _ in 0..20 {
+            if publisher.publish("kitchen", &json!({})).await.is_err() {
+                refused += 1;
+            }
+        }
+
+        assert!(refused > 0);
+    }
+
+    #[tokio::test]
+    async fn flush_disconnects_once_acknowledged() {
+        let broker = Broker::start().await;
+        let publisher = broker.publisher();
+        publisher.publish("kitchen", &json!({})).await.unwrap();
+        publisher.publish("hallway", &json!({})).await.unwrap();
+        broker.publishes(2).await;
+
+        broker.acks.add_permits(2);
+        timeout(Duration::from_secs(5), publisher.flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        timeout(Duration::from_secs(5), broker.until(|r| r.disconnected))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resends_unacknowledged_messages_after_reconnecting() {
+        let broker = Broker::start().await;
+        let publisher = broker.publisher();
+        publisher.publish("kitchen", &json!({})).await.unwrap();
+        broker.publishes(1).await;
+
+        broker.drop.notify_one();
+        broker.publishes(2).await;
+        broker.acks.add_permits(1);
+        timeout(Duration::from_secs(5), publisher.acknowledged())
+            .await
+            .unwrap();
+
+        let received = broker.received.lock().unwrap();
+        // The session is kept, so the broker can continue where it left off
+        assert_eq!(received.connects, vec![false, false]);
+        assert_eq!(received.publishes[0].pkid, received.publishes[1].pkid);
+    }
+
+    #[test]
+    fn formats_device_topic() {
+        assert_eq!(topic("sensors/{device}", "kitchen"), "sensors/kitchen");
+        assert_eq!(
+            topic("{device}/telemetry/{device}", "kitchen"),
+            "kitchen/telemetry/kitchen"
+        );
+        assert_eq!(topic("telemetry", "kitchen"), "telemetry");
+    }
+
+    #[test]
+    fn parses_qos() {
+        assert_eq!(parse_qos("1"), Ok(QoS::AtLeastOnce));
+        assert!(parse_qos("3").is_err());
+    }
+
+    #[test]
+    fn parses_commands() {
+        let command =
+            parse_command("command/inbox/kitchen/set-interval", b"{\"interval\":5}").unwrap();
+        assert_eq!(command.device, "kitchen");
+        assert_eq!(command.name, "set-interval");
+        assert_eq!(command.payload, b"{\"interval\":5}");
+
+        assert!(parse_command("set-interval", b"").is_none());
+        assert!(parse_command("command/inbox/kitchen/", b"").is_none());
+    }
+}
@@ -0,0 +1,353 @@
+use crate::http::PublishError;
+use crate::metrics;
+use crate::sink::Sink;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{sleep, Instant};
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    device: String,
+    timestamp: DateTime<Utc>,
+    payload: serde_json::Value,
+}
+
+/// Bounded queue storing one file per entry, named after its position in the queue.
+struct DiskQueue {
+    dir: PathBuf,
+    max_entries: usize,
+    entries: VecDeque<u64>,
+    next: u64,
+}
+
+impl DiskQueue {
+    fn open(dir: &Path, max_entries: usize) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut entries = Vec::new();
+        for file in std::fs::read_dir(dir)? {
+            let path = file?.path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if extension == Some("tmp") {
+                // Left behind by a crash while writing, the entry was never queued
+                std::fs::remove_file(&path)?;
+            } else if extension == Some("json") {
+                if let Some(seq) = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                {
+                    entries.push(seq);
+                }
+            }
+        }
+        entries.sort_unstable();
+        let next = entries.last().map(|s| s + 1).unwrap_or(0);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_entries: max_entries.max(1),
+            entries: entries.into(),
+            next,
+        })
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.json", seq))
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn push(&mut self, entry: &Entry) -> io::Result<()> {
+        while self.entries.len() >= self.max_entries {
+            log::warn!("Telemetry queue full, dropping oldest reading");
+            self.pop_front()?;
+        }
+        let seq = self.next;
+        // Write to a temporary file first, so that a crash never leaves a partial entry behind
+        let tmp = self.dir.join(format!("{:020}.tmp", seq));
+        std::fs::write(&tmp, serde_json::to_vec(entry)?)?;
+        std::fs::rename(&tmp, self.path(seq))?;
+        self.entries.push_back(seq);
+        self.next += 1;
+        Ok(())
+    }
+
+    /// The oldest entry and its position, which is needed to remove it.
+    fn front(&self) -> Option<(u64, io::Result<Entry>)> {
+        let seq = *self.entries.front()?;
+        let entry =
+            std::fs::read(self.path(seq)).and_then(|data| Ok(serde_json::from_slice(&data)?));
+        Some((seq, entry))
+    }
+
+    /// Removes the entry at `seq`, unless it was dropped already to make room for newer ones.
+    fn remove(&mut self, seq: u64) -> io::Result<()> {
+        if self.entries.front() == Some(&seq) {
+            self.pop_front()?;
+        }
+        Ok(())
+    }
+
+    fn pop_front(&mut self) -> io::Result<()> {
+        if let Some(seq) = self.entries.pop_front() {
+            match std::fs::remove_file(self.path(seq)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Persists telemetry to disk and forwards it in order to the underlying sink, so that
+/// readings survive uplink outages and gateway restarts.
+pub struct QueuedSink {
+    queue: Mutex<DiskQueue>,
+    notify: Notify,
+    drained: Notify,
+    sink: Sink,
+}
+
+impl QueuedSink {
+    pub fn open(dir: &Path, max_entries: usize, sink: Sink) -> io::Result<Self> {
+        let queue = DiskQueue::open(dir, max_entries)?;
+        if queue.len() > 0 {
+            log::info!("Resuming with {} queued readings", queue.len());
+        }
+        metrics::QUEUE_DEPTH.set(queue.len() as i64);
+        Ok(Self {
+            queue: Mutex::new(queue),
+            notify: Notify::new(),
+            drained: Notify::new(),
+            sink,
+        })
+    }
+
+    pub async fn push(&self, device: &str, payload: &serde_json::Value) -> io::Result<()> {
+        let entry = Entry {
+            device: device.to_string(),
+            timestamp: Utc::now(),
+            payload: payload.clone(),
+        };
+        let mut queue = self.queue.lock().await;
+        queue.push(&entry)?;
+        metrics::QUEUE_DEPTH.set(queue.len() as i64);
+        log::debug!(
+            "Queued reading for {} (queue depth {})",
+            device,
+            queue.len()
+        );
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Forwards queued readings to the sink, oldest first. Readings are published with the
+    /// time they were received by the gateway. A reading is only removed from disk once the
+    /// sink confirmed its delivery, so it may be published twice if the gateway stops in
+    /// between, but it is never lost.
+    pub async fn forward(&self) {
+        let mut delay = Duration::from_secs(1);
+        let mut failing = false;
+        loop {
+            let front = self.queue.lock().await.front();
+            let (seq, entry) = match front {
+                Some((seq, Ok(entry))) => (seq, entry),
+                None => {
+                    self.notify.notified().await;
+                    continue;
+                }
+                Some((seq, Err(e))) => {
+                    log::warn!("Discarding unreadable queued reading: {}", e);
+                    self.remove(seq).await;
+                    continue;
+                }
+            };
+
+            let mut payload = entry.payload;
+            if let serde_json::Value::Object(o) = &mut payload {
+                let timestamp = entry.timestamp;
+                o.entry("timestamp")
+                    .or_insert_with(|| serde_json::json!(timestamp));
+            }
+
+            match self.sink.publish(&entry.device, &payload).await {
+                Ok(()) => {
+                    self.sink.delivered().await;
+                    let depth = self.remove(seq).await;
+                    if failing {
+                        log::info!("Uplink restored, {} queued readings to replay", depth);
+                        failing = false;
+                    }
+                    delay = Duration::from_secs(1);
+                }
+                Err(e) if matches!(e.downcast_ref(), Some(PublishError::Rejected { .. })) => {
+                    log::warn!("Discarding queued reading for {}: {}", entry.device, e);
+                    self.remove(seq).await;
+                }
+                Err(e) => {
+                    let depth = self.queue.lock().await.len();
+                    log::warn!(
+                        "Error publishing queued telemetry ({} queued), retrying in {:?}: {}",
+                        depth,
+                        delay,
+                        e
+                    );
+                    failing = true;
+                    sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Waits until all queued readings are forwarded, then flushes the underlying sink.
+    /// Readings still queued at the deadline stay on disk and are published after a restart.
+    pub async fn flush(&self, deadline: Instant) -> anyhow::Result<()> {
+        let drained = tokio::time::timeout_at(deadline, async {
+            loop {
+                // Created before checking, so draining in between is not missed
+                let drained = self.drained.notified();
+                if self.queue.lock().await.len() == 0 {
+                    return;
+                }
+                drained.await;
+            }
+        });
+        match drained.await {
+            Ok(()) => self.sink.flush(deadline).await,
+            Err(_) => {
+                log::warn!(
+                    "{} readings remain queued, they are published after a restart",
+                    self.queue.lock().await.len()
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn remove(&self, seq: u64) -> usize {
+        let mut queue = self.queue.lock().await;
+        if let Err(e) = queue.remove(seq) {
+            log::warn!("Error removing queued reading: {}", e);
+        }
+        metrics::QUEUE_DEPTH.set(queue.len() as i64);
+        if queue.len() == 0 {
+            self.drained.notify_waiters();
+        }
+        queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn dir() -> PathBuf {
+        std::env::temp_dir().join(format!("queue-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn entry(value: u64) -> Entry {
+        Entry {
+            device: "microbit".to_string(),
+            timestamp: Utc::now(),
+            payload: json!({ "value": value }),
+        }
+    }
+
+    fn front_value(queue: &DiskQueue) -> Option<serde_json::Value> {
+        queue
+            .front()
+            .map(|(_, entry)| entry.unwrap().payload["value"].clone())
+    }
+
+    #[test]
+    fn persists_across_reopen() {
+        let dir = dir();
+        let mut queue = DiskQueue::open(&dir, 10).unwrap();
+        queue.push(&entry(1)).unwrap();
+        queue.push(&entry(2)).unwrap();
+        drop(queue);
+
+        let mut queue = DiskQueue::open(&dir, 10).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(front_value(&queue), Some(json!(1)));
+        queue.push(&entry(3)).unwrap();
+        let (seq, _) = queue.front().unwrap();
+        queue.remove(seq).unwrap();
+        assert_eq!(front_value(&queue), Some(json!(2)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes_partial_entries_on_open() {
+        let dir = dir();
+        let mut queue = DiskQueue::open(&dir, 10).unwrap();
+        queue.push(&entry(1)).unwrap();
+        drop(queue);
+        std::fs::write(dir.join(format!("{:020}.tmp", 1)), b"{\"dev").unwrap();
+
+        let queue = DiskQueue::open(&dir, 10).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let dir = dir();
+        let mut queue = DiskQueue::open(&dir, 2).unwrap();
+        queue.push(&entry(1)).unwrap();
+        let (sent, _) = queue.front().unwrap();
+        // Entry 1 is dropped while it is being published
+        queue.push(&entry(2)).unwrap();
+        queue.push(&entry(3)).unwrap();
+        assert_eq!(queue.len(), 2);
+
+        queue.remove(sent).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(front_value(&queue), Some(json!(2)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forwards_after_failure() {
+        let dir = dir();
+        let queue = Arc::new(QueuedSink::open(&dir, 10, Sink::Memory(Default::default())).unwrap());
+        if let Sink::Memory(memory) = &queue.sink {
+            memory.fail(2);
+        }
+        for value in 1..=3 {
+            queue
+                .push("microbit", &json!({ "value": value }))
+                .await
+                .unwrap();
+        }
+        let forwarder = queue.clone();
+        tokio::spawn(async move { forwarder.forward().await });
+
+        queue
+            .flush(Instant::now() + Duration::from_secs(10))
+            .await
+            .unwrap();
+
+        let values: Vec<_> = queue
+            .sink
+            .published()
+            .into_iter()
+            .map(|(_, payload)| payload["value"].clone())
+            .collect();
+        assert_eq!(values, vec![json!(1), json!(2), json!(3)]);
+        assert_eq!(queue.queue.lock().await.len(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
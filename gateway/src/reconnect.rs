@@ -0,0 +1,160 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How the gateway retries devices it fails to connect to or loses the connection to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectPolicy {
+    /// Delay after the first failure.
+    #[serde(with = "humantime_serde")]
+    pub initial_delay: Duration,
+    /// Upper bound for the delay, no matter how many attempts failed.
+    #[serde(with = "humantime_serde")]
+    pub max_delay: Duration,
+    /// Factor the delay grows by with each consecutive failure.
+    pub multiplier: f64,
+    /// Fraction of the delay to randomly add or subtract, so devices don't retry in lockstep.
+    pub jitter: f64,
+    /// Give up on a device after this many consecutive failures. Retries forever if not set.
+    pub max_attempts: Option<u32>,
+    /// Power cycle the adapter once every device failed this many times in a row.
+    pub power_cycle_after: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(300),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+            power_cycle_after: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.multiplier < 1.0 {
+            anyhow::bail!("reconnect.multiplier must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            anyhow::bail!("reconnect.jitter must be between 0 and 1");
+        }
+        if self.max_delay < self.initial_delay {
+            anyhow::bail!("reconnect.max_delay must not be less than reconnect.initial_delay");
+        }
+        Ok(())
+    }
+}
+
+/// What to do after a failed attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retry {
+    /// Wait this long before trying again.
+    After(Duration),
+    /// Stop trying.
+    GiveUp,
+}
+
+/// Consecutive failures of a single device.
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Records a successful attempt, so the next failure starts over with the initial delay.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Records a failed attempt.
+    pub fn failed(&mut self) -> Retry {
+        self.failures += 1;
+        if matches!(self.policy.max_attempts, Some(max) if self.failures >= max) {
+            return Retry::GiveUp;
+        }
+
+        Retry::After(self.delay(rand::thread_rng().gen_range(-1.0..=1.0)))
+    }
+
+    /// Delay after the current number of failures, with `spread` between -1 and 1 selecting
+    /// the jitter.
+    fn delay(&self, spread: f64) -> Duration {
+        let policy = &self.policy;
+        let exponent = self.failures.saturating_sub(1).min(64) as i32;
+        let delay = (policy.initial_delay.as_secs_f64() * policy.multiplier.powi(exponent))
+            .min(policy.max_delay.as_secs_f64());
+        Duration::from_secs_f64(delay * (1.0 + policy.jitter * spread))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_max() {
+        let mut backoff = Backoff::new(policy());
+        let delays: Vec<Retry> = (0..6).map(|_| backoff.failed()).collect();
+
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 10, 10]
+                .iter()
+                .map(|s| Retry::After(Duration::from_secs(*s)))
+                .collect::<Vec<_>>()
+        );
+
+        backoff.reset();
+        assert_eq!(backoff.failed(), Retry::After(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            jitter: 0.5,
+            ..policy()
+        });
+        backoff.failed();
+        backoff.failed();
+
+        assert_eq!(backoff.delay(-1.0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(1.0), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            max_attempts: Some(3),
+            ..policy()
+        });
+
+        assert!(matches!(backoff.failed(), Retry::After(_)));
+        assert!(matches!(backoff.failed(), Retry::After(_)));
+        assert_eq!(backoff.failed(), Retry::GiveUp);
+    }
+}
@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A raw characteristic value as received from a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub timestamp: DateTime<Utc>,
+    pub device: String,
+    pub address: String,
+    pub characteristic: uuid::Uuid,
+    pub data: Vec<u8>,
+    /// Firmware revision of the device, which decides how some values are decoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_revision: Option<String>,
+}
+
+/// Appends raw values to a recording, one JSON record per line.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Recorder for the values of a single device.
+    pub fn device(self: &Arc<Self>, device: &str, address: &str) -> DeviceRecorder {
+        DeviceRecorder {
+            recorder: self.clone(),
+            device: device.to_string(),
+            address: address.to_string(),
+            firmware_revision: None,
+        }
+    }
+
+    fn write(&self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
+
+#[derive(Clone)]
+pub struct DeviceRecorder {
+    recorder: Arc<Recorder>,
+    device: String,
+    address: String,
+    firmware_revision: Option<String>,
+}
+
+impl DeviceRecorder {
+    /// Records values as coming from a device running this firmware revision.
+    pub fn with_firmware_revision(mut self, revision: Option<&str>) -> Self {
+        self.firmware_revision = revision.map(str::to_string);
+        self
+    }
+
+    /// Records a value. Failing to record is logged, it never interrupts streaming.
+    pub fn record(&self, characteristic: uuid::Uuid, data: &[u8]) {
+        let record = Record {
+            timestamp: Utc::now(),
+            device: self.device.clone(),
+            address: self.address.clone(),
+            characteristic,
+            data: data.to_vec(),
+            firmware_revision: self.firmware_revision.clone(),
+        };
+        if let Err(e) = self.recorder.write(&record) {
+            log::warn!("Error recording value of {}: {}", self.device, e);
+        }
+    }
+}
+
+/// Reads all records of a recording.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            records.push(serde_json::from_str(&line).map_err(|e| {
+                anyhow::anyhow!("{}:{}: invalid record: {}", path.display(), n + 1, e)
+            })?);
+        }
+    }
+    Ok(records)
+}
+
+/// Yields the records with the delays they were received with, divided by `speed`. Fails if a
+/// delay is too long to wait for, as with a speed close to zero.
+pub fn replay(records: Vec<Record>, speed: f64) -> anyhow::Result<impl Stream<Item = Record>> {
+    let start = records.first().map(|r| r.timestamp);
+    let begin = tokio::time::Instant::now();
+    let mut scheduled = Vec::with_capacity(records.len());
+    for record in records {
+        let offset = (record.timestamp - start.unwrap())
+            .to_std()
+            .unwrap_or_default();
+        let at = Duration::try_from_secs_f64(offset.as_secs_f64() / speed)
+            .ok()
+            .and_then(|delay| begin.checked_add(delay))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "replay speed {} is too slow for values recorded {:?} apart",
+                    speed,
+                    offset
+                )
+            })?;
+        scheduled.push((at, record));
+    }
+    Ok(
+        futures::stream::iter(scheduled).then(|(at, record)| async move {
+            tokio::time::sleep_until(at).await;
+            record
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(offset: i64, data: &[u8]) -> Record {
+        Record {
+            timestamp: DateTime::parse_from_rfc3339("2022-05-13T10:00:00+00:00")
+                .unwrap()
+                .with_timezone(&Utc)
+                + chrono::Duration::seconds(offset),
+            device: "microbit".to_string(),
+            address: "E2:9A:A8:1C:CB:0A".to_string(),
+            characteristic: crate::decoders::TEMPERATURE_CELSIUS_UUID,
+            data: data.to_vec(),
+            firmware_revision: None,
+        }
+    }
+
+    #[test]
+    fn reads_recorded_values() {
+        let path = std::env::temp_dir().join(format!("recording-{}.jsonl", uuid::Uuid::new_v4()));
+        let recorder = Arc::new(Recorder::create(&path).unwrap());
+        let device = recorder
+            .device("microbit", "E2:9A:A8:1C:CB:0A")
+            .with_firmware_revision(Some("1.0"));
+        device.record(crate::decoders::TEMPERATURE_CELSIUS_UUID, &[215, 0]);
+        device.record(crate::decoders::BATTERY_LEVEL_UUID, &[90]);
+
+        let records = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].device, "microbit");
+        assert_eq!(records[0].data, vec![215, 0]);
+        assert_eq!(records[0].firmware_revision.as_deref(), Some("1.0"));
+        assert_eq!(
+            records[1].characteristic,
+            crate::decoders::BATTERY_LEVEL_UUID
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_at_accelerated_speed() {
+        let start = tokio::time::Instant::now();
+        let records = vec![record(0, &[1]), record(10, &[2]), record(30, &[3])];
+
+        let offsets: Vec<u64> = replay(records, 10.0)
+            .unwrap()
+            .map(|_| start.elapsed().as_secs())
+            .collect()
+            .await;
+
+        assert_eq!(offsets, vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn rejects_speeds_that_delay_forever() {
+        let records = vec![record(0, &[1]), record(10, &[2])];
+        assert!(replay(records, 1e-300).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use crate::board::{
+    BOARD_SERVICE_UUID, DEVICE_INFO_SERVICE_UUID, FIRMWARE_REVISION_CHAR_UUID, INTERVAL_CHAR_UUID,
+    MODEL_NUMBER_CHAR_UUID,
+};
+use crate::decoders::TEMPERATURE_CELSIUS_UUID;
+use crate::ess::MEASUREMENT_DESCRIPTOR_UUID;
+use crate::mock::MockPeer;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// Shape of the simulated temperature over one period.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Constant,
+    Sine,
+    Square,
+    Sawtooth,
+}
+
+impl Waveform {
+    /// Value between -1 and 1 at `phase`, the fraction of the period elapsed.
+    fn value(&self, phase: f64) -> f64 {
+        match self {
+            Self::Constant => 0.0,
+            Self::Sine => (phase * 2.0 * std::f64::consts::PI).sin(),
+            Self::Square if phase < 0.5 => 1.0,
+            Self::Square => -1.0,
+            Self::Sawtooth => phase * 2.0 - 1.0,
+        }
+    }
+}
+
+/// Simulated boards, for running the gateway without hardware.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulatorConfig {
+    /// Simulate the configured devices instead of connecting to them.
+    pub enabled: bool,
+    pub waveform: Waveform,
+    /// Temperature in °C the waveform is centered on.
+    pub base: f64,
+    /// Largest deviation from `base` in °C, not counting noise.
+    pub amplitude: f64,
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    /// Random noise of up to this many °C added to each reading.
+    pub noise: f64,
+    /// Probability of a notification not being sent.
+    pub dropout: f64,
+    /// Drop the connection this often, to exercise reconnecting.
+    #[serde(with = "humantime_serde")]
+    pub disconnect_every: Option<Duration>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            waveform: Waveform::Sine,
+            base: 21.0,
+            amplitude: 3.0,
+            period: Duration::from_secs(600),
+            noise: 0.2,
+            dropout: 0.0,
+            disconnect_every: None,
+        }
+    }
+}
+
+impl SimulatorConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.period.is_zero() {
+            anyhow::bail!("simulator.period must be greater than 0");
+        }
+        if self.noise < 0.0 {
+            anyhow::bail!("simulator.noise must not be negative");
+        }
+        if !(0.0..=1.0).contains(&self.dropout) {
+            anyhow::bail!("simulator.dropout must be between 0 and 1");
+        }
+        Ok(())
+    }
+
+    /// Temperature after `elapsed`, with `noise` between -1 and 1 selecting the noise.
+    fn temperature(&self, elapsed: Duration, noise: f64) -> f64 {
+        let phase = (elapsed.as_secs_f64() / self.period.as_secs_f64()).fract();
+        self.base + self.amplitude * self.waveform.value(phase) + self.noise * noise
+    }
+}
+
+/// Creates a simulated board and starts sending notifications from it. Notifications are
+/// sent as often as the interval characteristic says, so interval changes are honoured.
+pub fn spawn(config: SimulatorConfig, interval: u8) -> MockPeer {
+    let peer = MockPeer::new();
+    peer.add_characteristic(
+        DEVICE_INFO_SERVICE_UUID,
+        MODEL_NUMBER_CHAR_UUID,
+        b"Simulator",
+    );
+    // Unlike the board firmware, the simulator sends tenths of a degree
+    peer.add_characteristic(
+        DEVICE_INFO_SERVICE_UUID,
+        FIRMWARE_REVISION_CHAR_UUID,
+        b"simulator",
+    );
+    peer.add_characteristic(
+        BOARD_SERVICE_UUID,
+        TEMPERATURE_CELSIUS_UUID,
+        &encode(config.temperature(Duration::from_secs(0), 0.0)),
+    );
+    peer.add_characteristic(BOARD_SERVICE_UUID, INTERVAL_CHAR_UUID, &[interval]);
+    // Mean air temperature updated every interval, as described by the board firmware
+    peer.add_descriptor(
+        TEMPERATURE_CELSIUS_UUID,
+        MEASUREMENT_DESCRIPTOR_UUID,
+        &[0, 0, 2, 0, 0, 0, interval, 0, 0, 1, 0xff],
+    );
+    tokio::spawn(simulate(config, peer.clone()));
+    peer
+}
+
+/// Temperature Celsius characteristic value, in tenths of a degree.
+fn encode(temperature: f64) -> [u8; 2] {
+    ((temperature * 10.0).round() as i16).to_le_bytes()
+}
+
+async fn simulate(config: SimulatorConfig, peer: MockPeer) {
+    let start = Instant::now();
+    let mut connected_since = start;
+    loop {
+        let interval = peer
+            .value(INTERVAL_CHAR_UUID)
+            .and_then(|v| v.first().copied())
+            .unwrap_or(1)
+            .max(1);
+        sleep(Duration::from_secs(interval as u64)).await;
+
+        if !peer.is_connected() {
+            connected_since = Instant::now();
+            continue;
+        }
+        if matches!(config.disconnect_every, Some(every) if connected_since.elapsed() >= every) {
+            log::info!("Simulating disconnect");
+            peer.disconnect();
+            continue;
+        }
+        let (dropped, noise) = {
+            let mut rng = rand::thread_rng();
+            (rng.gen_bool(config.dropout), rng.gen_range(-1.0..=1.0))
+        };
+        if dropped {
+            log::debug!("Simulating dropped notification");
+            continue;
+        }
+        let temperature = config.temperature(start.elapsed(), noise);
+        peer.notify(TEMPERATURE_CELSIUS_UUID, &encode(temperature));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Microbit;
+    use futures::StreamExt;
+    use serde_json::json;
+
+    fn config(waveform: Waveform) -> SimulatorConfig {
+        SimulatorConfig {
+            enabled: true,
+            waveform,
+            base: 20.0,
+            amplitude: 4.0,
+            period: Duration::from_secs(40),
+            noise: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn follows_waveform() {
+        let at =
+            |config: &SimulatorConfig, secs| config.temperature(Duration::from_secs(secs), 0.0);
+
+        let sine = config(Waveform::Sine);
+        assert_eq!(at(&sine, 10), 24.0);
+        assert_eq!(at(&sine, 50), 24.0);
+
+        let square = config(Waveform::Square);
+        assert_eq!(at(&square, 0), 24.0);
+        assert_eq!(at(&square, 20), 16.0);
+
+        let sawtooth = config(Waveform::Sawtooth);
+        assert_eq!(at(&sawtooth, 0), 16.0);
+        assert_eq!(at(&sawtooth, 30), 22.0);
+        assert_eq!(
+            config(Waveform::Constant).temperature(Duration::from_secs(7), -1.0),
+            20.0
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_like_a_board() {
+        let peer = spawn(config(Waveform::Square), 5);
+        let mut board = Microbit::with_client(peer.client());
+        board.set_interval(10).await.unwrap();
+        let mut s = board.stream_sensors().await.unwrap();
+
+        let start = Instant::now();
+        let reading = json!({ "temperature": { "value": 24.0, "unit": "°C" } });
+        assert_eq!(s.next().await.unwrap().unwrap(), reading);
+        // Already scheduled with the interval the simulator started with
+        assert_eq!(s.next().await.unwrap().unwrap(), reading);
+        s.next().await.unwrap().unwrap();
+        let before = start.elapsed();
+        s.next().await.unwrap().unwrap();
+        assert_eq!(start.elapsed() - before, Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_periodically() {
+        let peer = spawn(
+            SimulatorConfig {
+                disconnect_every: Some(Duration::from_secs(20)),
+                ..config(Waveform::Constant)
+            },
+            5,
+        );
+        let mut board = Microbit::with_client(peer.client());
+        let s = board.stream_sensors().await.unwrap();
+
+        // Initial value and notifications until the disconnect
+        assert!(s.count().await >= 4);
+        assert!(!peer.is_connected());
+    }
+}
@@ -0,0 +1,123 @@
+use crate::cloudevents::Events;
+use crate::http::HttpPublisher;
+use crate::metrics;
+use crate::mqtt::MqttPublisher;
+use crate::queue::QueuedSink;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+/// Destination for the telemetry produced by the gateway.
+pub enum Sink {
+    Stdout,
+    Http(HttpPublisher),
+    Mqtt(MqttPublisher),
+    Queued(Arc<QueuedSink>),
+    /// Wraps payloads as structured mode CloudEvents before passing them on.
+    Events(Events, Box<Sink>),
+    #[cfg(test)]
+    Memory(MemorySink),
+}
+
+/// Keeps the published payloads, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySink {
+    published: std::sync::Mutex<Vec<(String, serde_json::Value)>>,
+    failures: std::sync::atomic::AtomicUsize,
+    delay: std::sync::Mutex<std::time::Duration>,
+}
+
+#[cfg(test)]
+impl MemorySink {
+    /// Makes the next `n` publishes fail.
+    pub fn fail(&self, n: usize) {
+        self.failures.store(n, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Makes every publish take `delay`, like an uplink that is slow to respond.
+    pub fn slow_down(&self, delay: std::time::Duration) {
+        *self.delay.lock().unwrap() = delay;
+    }
+
+    async fn publish(&self, device: &str, payload: &serde_json::Value) -> anyhow::Result<()> {
+        use std::sync::atomic::Ordering;
+        let delay = *self.delay.lock().unwrap();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        let failing = self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if failing {
+            anyhow::bail!("uplink unavailable");
+        }
+        self.published
+            .lock()
+            .unwrap()
+            .push((device.to_string(), payload.clone()));
+        Ok(())
+    }
+}
+
+impl Sink {
+    pub async fn publish(&self, device: &str, payload: &serde_json::Value) -> anyhow::Result<()> {
+        let result = match self {
+            Self::Stdout => {
+                println!("{}", payload);
+                Ok(())
+            }
+            Self::Http(http) => http.publish(device, payload).await.map_err(Into::into),
+            // Counted once the broker acknowledges the message
+            Self::Mqtt(mqtt) => return mqtt.publish(device, payload).await,
+            Self::Queued(queue) => return Ok(queue.push(device, payload).await?),
+            Self::Events(events, sink) => {
+                let event = events.structured(device, payload);
+                return Box::pin(sink.publish(device, &event)).await;
+            }
+            #[cfg(test)]
+            Self::Memory(memory) => memory.publish(device, payload).await,
+        };
+        let label = if result.is_ok() { "success" } else { "failure" };
+        metrics::PUBLISHED.with_label_values(&[label]).inc();
+        result
+    }
+
+    /// Waits until everything published so far was delivered. Only MQTT publishes return
+    /// before the uplink confirmed the delivery.
+    pub async fn delivered(&self) {
+        match self {
+            Self::Mqtt(mqtt) => mqtt.acknowledged().await,
+            Self::Events(_, sink) => Box::pin(sink.delivered()).await,
+            _ => {}
+        }
+    }
+
+    /// Sends whatever is still pending before the gateway exits, giving up at the deadline.
+    pub async fn flush(&self, deadline: Instant) -> anyhow::Result<()> {
+        match self {
+            Self::Stdout => Ok(std::io::stdout().flush()?),
+            // Every publish completes before returning
+            Self::Http(_) => Ok(()),
+            Self::Mqtt(mqtt) => Ok(tokio::time::timeout_at(deadline, mqtt.flush())
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("timed out waiting for the MQTT broker to acknowledge messages")
+                })??),
+            Self::Queued(queue) => Box::pin(queue.flush(deadline)).await,
+            Self::Events(_, sink) => Box::pin(sink.flush(deadline)).await,
+            #[cfg(test)]
+            Self::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Payloads published to a `Memory` sink so far.
+    #[cfg(test)]
+    pub fn published(&self) -> Vec<(String, serde_json::Value)> {
+        match self {
+            Self::Memory(memory) => memory.published.lock().unwrap().clone(),
+            _ => Vec::new(),
+        }
+    }
+}
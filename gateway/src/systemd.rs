@@ -0,0 +1,194 @@
+use crate::api::{DeviceState, DeviceStates};
+use sd_notify::NotifyState;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often the status is updated when the watchdog is not enabled.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often loops that are waiting for something beat, to show they are not stuck.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When each of the gateway's loops last made progress. The watchdog is only fed while all
+/// of them do.
+#[derive(Debug, Default)]
+pub struct Heartbeats {
+    loops: Mutex<HashMap<u64, (String, Instant)>>,
+    next: AtomicU64,
+}
+
+impl Heartbeats {
+    /// Starts tracking a loop, which has to beat until the returned heartbeat is dropped.
+    pub fn register(self: &Arc<Self>, name: &str) -> Heartbeat {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        self.loops
+            .lock()
+            .unwrap()
+            .insert(id, (name.to_string(), Instant::now()));
+        Heartbeat {
+            heartbeats: self.clone(),
+            id,
+        }
+    }
+
+    /// The first loop found that made no progress for longer than `timeout`.
+    pub fn stale(&self, timeout: Duration) -> Option<String> {
+        self.loops
+            .lock()
+            .unwrap()
+            .values()
+            .find(|(_, last)| last.elapsed() > timeout)
+            .map(|(name, _)| name.clone())
+    }
+}
+
+/// Heartbeat of a single loop, which stops being tracked when dropped.
+#[derive(Debug)]
+pub struct Heartbeat {
+    heartbeats: Arc<Heartbeats>,
+    id: u64,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        if let Some((_, last)) = self.heartbeats.loops.lock().unwrap().get_mut(&self.id) {
+            *last = Instant::now();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.heartbeats.loops.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Integration with systemd, for running the gateway as a `Type=notify` service.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemdConfig {
+    /// Tell systemd when the gateway is ready and how many devices are connected, and keep its
+    /// watchdog fed if `WatchdogSec` is set.
+    pub notify: bool,
+}
+
+/// Sends state changes to systemd, if enabled. Does nothing when not run by systemd.
+#[derive(Debug, Clone, Copy)]
+pub struct Notifier {
+    enabled: bool,
+}
+
+impl Notifier {
+    pub fn new(config: &SystemdConfig) -> Self {
+        Self {
+            enabled: config.notify,
+        }
+    }
+
+    fn notify(&self, state: &[NotifyState]) {
+        if !self.enabled {
+            return;
+        }
+        if let Err(e) = sd_notify::notify(false, state) {
+            log::warn!("Error notifying systemd: {}", e);
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify(&[NotifyState::Ready]);
+    }
+
+    pub fn stopping(&self) {
+        self.notify(&[NotifyState::Stopping]);
+    }
+
+    /// Reports the connected devices whenever they change, and pings the watchdog at half its
+    /// timeout. Pings are skipped once a heartbeat is older than the timeout, so systemd
+    /// restarts the gateway when one of its loops is stuck, not just when the runtime is blocked.
+    pub async fn supervise(self, states: Arc<DeviceStates>, heartbeats: Arc<Heartbeats>) {
+        if !self.enabled {
+            return;
+        }
+        let mut usec = 0;
+        let watchdog =
+            sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec));
+        let period = match watchdog {
+            Some(timeout) => {
+                log::info!("Pinging systemd watchdog, timeout {:?}", timeout);
+                timeout / 2
+            }
+            None => STATUS_INTERVAL,
+        };
+
+        let mut interval = tokio::time::interval(period);
+        let mut reported = String::new();
+        loop {
+            interval.tick().await;
+            let status = status(&states.all());
+            let mut state = Vec::new();
+            if status != reported {
+                state.push(NotifyState::Status(&status));
+            }
+            if let Some(timeout) = watchdog {
+                match heartbeats.stale(timeout) {
+                    Some(name) => log::warn!(
+                        "No progress of {} for {:?}, not pinging systemd watchdog",
+                        name,
+                        timeout
+                    ),
+                    None => state.push(NotifyState::Watchdog),
+                }
+            }
+            if !state.is_empty() {
+                self.notify(&state);
+            }
+            reported = status;
+        }
+    }
+}
+
+/// Status line shown by `systemctl status`.
+fn status(devices: &BTreeMap<String, DeviceState>) -> String {
+    let connected = devices.values().filter(|d| d.connected).count();
+    format!("{} of {} devices connected", connected, devices.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_connected_devices() {
+        let states = DeviceStates::default();
+        assert_eq!(status(&states.all()), "0 of 0 devices connected");
+
+        states.update("kitchen", "E2:9A:A8:1C:CB:0A", |s| s.connected = true);
+        states.update("garage", "C1:5A:1B:00:41:7E", |_| {});
+        assert_eq!(status(&states.all()), "1 of 2 devices connected");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_go_stale_without_progress() {
+        let heartbeats = Arc::new(Heartbeats::default());
+        let discovery = heartbeats.register("discovery");
+        let device = heartbeats.register("kitchen");
+        let timeout = Duration::from_secs(10);
+
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        assert_eq!(heartbeats.stale(timeout), None);
+        discovery.beat();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        // One stuck loop is enough
+        assert_eq!(heartbeats.stale(timeout), Some("kitchen".to_string()));
+
+        // Loops that ended don't count
+        drop(device);
+        assert_eq!(heartbeats.stale(timeout), None);
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        assert_eq!(heartbeats.stale(timeout), Some("discovery".to_string()));
+    }
+}